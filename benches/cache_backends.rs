@@ -0,0 +1,143 @@
+//! Walks of the same tree with the sqlite and the redb caches:
+//! `cold` fills an empty cache, `warm` only hits the cache
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rusqlite::Connection;
+use sausage::{setup_sqlite_cache, FsEntry, FsProcessor, MemoizedFsWalker, RedbCache, SpecialKind};
+use tempdir::TempDir;
+
+const FOLDERS: usize = 50;
+const FILES_PER_FOLDER: usize = 100;
+
+/// sums file sizes, folders get the size of their content
+struct SizeProcessor;
+
+impl FsProcessor for SizeProcessor {
+    type Item = i64;
+
+    fn process_file(
+        &mut self,
+        path: &Path,
+        _mount_path: &Path,
+        _previous: Option<i64>,
+    ) -> Result<i64> {
+        Ok(path.metadata()?.len() as i64)
+    }
+
+    fn process_symlink(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _previous: Option<i64>,
+    ) -> Result<i64> {
+        Ok(0)
+    }
+
+    fn process_special(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _kind: SpecialKind,
+        _previous: Option<i64>,
+    ) -> Result<i64> {
+        Ok(0)
+    }
+
+    fn process_folder(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        sub: HashMap<PathBuf, FsEntry<i64>>,
+        _previous: Option<i64>,
+    ) -> Result<i64> {
+        Ok(sub.values().map(|entry| entry.item).sum())
+    }
+}
+
+fn new_tree(tmpdir: &TempDir) -> Result<PathBuf> {
+    let root = tmpdir.path().join("tree");
+    for folder in 0..FOLDERS {
+        let folder = root.join(format!("d{}", folder));
+        create_dir_all(&folder)?;
+        for file in 0..FILES_PER_FOLDER {
+            let mut f = File::create(folder.join(format!("f{}", file)))?;
+            writeln!(&mut f, "{}", file)?;
+        }
+    }
+    Ok(root)
+}
+
+fn walk_sqlite(db: &mut Connection, root: &Path) -> Result<i64> {
+    let tx = db.transaction()?;
+    let mut adder = MemoizedFsWalker::new(&*tx).start_processing(SizeProcessor)?;
+    let entry = adder.add_path(root, "tree")?;
+    adder.finish_processing()?;
+    tx.commit()?;
+    Ok(entry.item)
+}
+
+fn walk_redb(cache: &RedbCache, root: &Path) -> Result<i64> {
+    let mut adder = MemoizedFsWalker::new(cache).start_processing(SizeProcessor)?;
+    let entry = adder.add_path(root, "tree")?;
+    adder.finish_processing()?;
+    Ok(entry.item)
+}
+
+fn open_sqlite(path: &Path) -> Connection {
+    let _ = std::fs::remove_file(path);
+    let db = Connection::open(path).unwrap();
+    setup_sqlite_cache(&db).unwrap();
+    db
+}
+
+fn open_redb(path: &Path) -> RedbCache {
+    let _ = std::fs::remove_file(path);
+    RedbCache::open(path).unwrap()
+}
+
+fn cache_backends(c: &mut Criterion) {
+    let tmpdir = TempDir::new("sausage_bench").unwrap();
+    let root = new_tree(&tmpdir).unwrap();
+    let sqlite_path = tmpdir.path().join("cache.db");
+    let redb_path = tmpdir.path().join("cache.redb");
+
+    let mut group = c.benchmark_group("cold");
+    group.sample_size(10);
+    group.bench_function("sqlite", |b| {
+        b.iter_batched(
+            || open_sqlite(&sqlite_path),
+            |mut db| walk_sqlite(&mut db, &root).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("redb", |b| {
+        b.iter_batched(
+            || open_redb(&redb_path),
+            |cache| walk_redb(&cache, &root).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("warm");
+    group.sample_size(10);
+    let mut db = open_sqlite(&sqlite_path);
+    walk_sqlite(&mut db, &root).unwrap();
+    group.bench_function("sqlite", |b| {
+        b.iter(|| walk_sqlite(&mut db, &root).unwrap())
+    });
+    let cache = open_redb(&redb_path);
+    walk_redb(&cache, &root).unwrap();
+    group.bench_function("redb", |b| b.iter(|| walk_redb(&cache, &root).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, cache_backends);
+criterion_main!(benches);
@@ -1,27 +1,32 @@
 use std::{
     fs::{read_dir, File},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{anyhow, Result};
-use clap::{AppSettings, Clap};
+use anyhow::{anyhow, bail, Result};
+use clap::{AppSettings, ArgSettings, Clap};
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use rusqlite::Connection;
-use sausage::{rollback_before_session_id, setup_sqlite_cache, MemoizedFsWalker, TarProcessor};
+use rusqlite::{Connection, OpenFlags};
+use sausage::{
+    list_sessions, rollback_before_session_id, setup_sqlite_cache, sqlite_schema_version,
+    ChangeAction, ChangeDetector, ChangeEvent, ChangeNotifier, ChangeSink, ExcludeRules, FsNode,
+    FsNodeType, FsProcessor, MemoizedFsWalker, SessionReport, TarProcessor, SQLITE_SCHEMA_VERSION,
+};
 
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
 #[derive(Clap)]
-#[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::ColoredHelp, setting = AppSettings::SubcommandsNegateReqs)]
 struct Opts {
     /// Output tar file that will contain only changed files since the last run
-    #[clap(short, long)]
-    output_tar: PathBuf,
+    #[clap(short, long, setting = ArgSettings::Required)]
+    output_tar: Option<PathBuf>,
     /// Cache database to use for this execution, will be updated with a transaction when the tar is generated
-    #[clap(short, long)]
-    cache_db: PathBuf,
+    #[clap(short, long, setting = ArgSettings::Required)]
+    cache_db: Option<PathBuf>,
     /// Rollback to a previous session id before execution
     #[clap(short, long)]
     rollback: Option<u32>,
@@ -34,69 +39,372 @@ struct Opts {
     #[clap(short = 'l', long, default_value = "6")]
     compress_level: u32,
 
+    /// Label saved with the session, see the sessions command
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Find the entries moved since the last run by their inode, they are archived as a '<name>.MOVED' file
+    /// holding their new path instead of being archived again
+    #[clap(short = 'm', long)]
+    detect_moves: bool,
+
+    #[clap(flatten)]
+    walk: WalkOpts,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Print the entries added, changed or removed since the last run, without updating the cache
+    Status(StatusOpts),
+    /// List the sessions saved in the cache, with the tar file each one produced
+    Sessions(SessionsOpts),
+}
+
+#[derive(Clap)]
+struct SessionsOpts {
+    /// Cache database to read
+    #[clap(short, long)]
+    cache_db: PathBuf,
+}
+
+#[derive(Clap)]
+struct StatusOpts {
+    /// Cache database to compare with, it is left untouched
+    #[clap(short, long)]
+    cache_db: PathBuf,
+
+    #[clap(flatten)]
+    walk: WalkOpts,
+}
+
+/// Settings shared by the commands walking the input paths
+#[derive(Clap)]
+struct WalkOpts {
+    /// Cache namespace, each namespace tracks its own changes in the same cache database
+    #[clap(short, long)]
+    namespace: Option<String>,
+
+    /// How to detect changed entries: mtime, size-mtime, inode-ctime-size or content-hash.
+    /// Only inode-ctime-size sees mode and owner changes, a file whose content is the same is then archived as a header-only
+    /// '<name>.METADATA' file. A file whose ctime alone changed, like after an extended attribute change,
+    /// is archived again, as it can not be told apart from a rewrite restoring its mtime
+    #[clap(short = 'd', long, default_value = "mtime", parse(try_from_str = parse_change_detector))]
+    change_detector: ChangeDetector,
+
+    /// Entries modified less than this many milliseconds before the run are checked again by the next run, 0 to disable
+    #[clap(long, default_value = "1000")]
+    racy_window_ms: u64,
+
+    /// Skip the entries that can not be read instead of aborting, their previous state is kept in the cache
+    #[clap(short, long)]
+    keep_going: bool,
+
+    /// Skip the entries matching this pattern, using the .gitignore syntax, can be repeated
+    #[clap(short, long, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Also read exclusion rules from .gitignore and .sausageignore files
+    #[clap(short = 'i', long)]
+    ignore_files: bool,
+
+    /// Print a summary of the added, changed, removed, moved and unchanged entries
+    #[clap(short = 's', long)]
+    stats: bool,
+
     /// file/folder to include into the tar file use <local path> or <local path>:<tar path>
     #[clap(parse(try_from_str = parse_input_path))]
     input_paths: Vec<(PathBuf, Option<PathBuf>)>,
 }
 
+impl WalkOpts {
+    /// the default namespace is empty
+    fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or_default()
+    }
+
+    fn walker<'c>(&self, cache: &'c Connection) -> MemoizedFsWalker<FsNode, &'c Connection> {
+        let mut rules = if self.ignore_files {
+            ExcludeRules::gitignore()
+        } else {
+            ExcludeRules::new()
+        };
+        for pattern in &self.exclude {
+            rules = rules.add_pattern(pattern);
+        }
+        MemoizedFsWalker::new(cache)
+            .namespace(self.namespace())
+            .change_detector(self.change_detector)
+            .tolerate_errors(self.keep_going)
+            .racy_window(Some(Duration::from_millis(self.racy_window_ms)).filter(|w| !w.is_zero()))
+            .exclude(rules)
+    }
+
+    /// walk every input path and finish the session
+    fn walk<F: FsProcessor<Item = FsNode>>(
+        &self,
+        walker: MemoizedFsWalker<FsNode, &Connection>,
+        proc: F,
+        label: Option<&str>,
+        artifact: Option<&Path>,
+    ) -> Result<SessionReport> {
+        let mut adder = walker.start_processing(proc)?;
+        if let Some(label) = label {
+            adder.set_label(label);
+        }
+        if let Some(artifact) = artifact {
+            adder.set_artifact(artifact.to_string_lossy());
+        }
+        for (path, opt_mount_path) in &self.input_paths {
+            if let Some(mount_path) = opt_mount_path {
+                let path = path.canonicalize()?;
+                adder.add_path(path, mount_path)?;
+            } else {
+                if let Some(sub) = path.file_name() {
+                    let path = path.canonicalize()?;
+                    adder.add_path(&path, sub)?;
+                } else {
+                    //this is a folder, add all sub entries instead
+                    for sub in read_dir(path)? {
+                        let entry = sub?;
+                        adder.add_path(entry.path().canonicalize()?, entry.file_name())?;
+                    }
+                }
+            }
+        }
+        let (_walker, report) = adder.finish_processing()?;
+        for error in &report.errors {
+            eprintln!("skipped {}: {:#}", error.path.display(), error.error);
+        }
+        if self.stats {
+            print_report(&report);
+        }
+        Ok(report)
+    }
+}
+
 fn parse_input_path(input_path: &str) -> Result<(PathBuf, Option<PathBuf>)> {
     let mut split = input_path.split(':');
     let path = split
         .next()
         .ok_or_else(|| anyhow!("input_path is malformed, use <path> or <path>:<mount_path>"))?;
     let path = PathBuf::from(path);
-    let mount_path = split.next().map(|p| PathBuf::from(p));
+    let mount_path = split.next().map(PathBuf::from);
     Ok((path, mount_path))
 }
 
-fn run<W: Write>(mut writer: W, mut cache: Connection, opts: &Opts) -> Result<()> {
+fn parse_change_detector(detector: &str) -> Result<ChangeDetector> {
+    match detector {
+        "mtime" => Ok(ChangeDetector::Mtime),
+        "size-mtime" => Ok(ChangeDetector::SizeMtime),
+        "inode-ctime-size" => Ok(ChangeDetector::InodeCtimeSize),
+        "content-hash" => Ok(ChangeDetector::ContentHash),
+        _ => Err(anyhow!(
+            "unknown change detector, use mtime, size-mtime, inode-ctime-size or content-hash"
+        )),
+    }
+}
+
+fn run<W: Write>(mut writer: W, mut cache: Connection, opts: &Opts, tar: &Path) -> Result<()> {
     let proc = TarProcessor::new(&mut writer);
 
     let tx = cache.transaction()?;
 
     if let Some(rollback_id) = opts.rollback {
-        rollback_before_session_id(&*tx, rollback_id)?;
+        rollback_before_session_id(&tx, opts.walk.namespace(), rollback_id)?;
     }
-    let walker = MemoizedFsWalker::new(&*tx);
-    let mut adder = walker.start_processing(proc)?;
-    for (path, opt_mount_path) in &opts.input_paths {
-        if let Some(mount_path) = opt_mount_path {
-            let path = path.canonicalize()?;
-            adder.add_path(path, mount_path)?;
-        } else {
-            if let Some(sub) = path.file_name() {
-                let path = path.canonicalize()?;
-                adder.add_path(&path, sub)?;
-            } else {
-                //this is a folder, add all sub entries instead
-                for sub in read_dir(path)? {
-                    let entry = sub?;
-                    adder.add_path(entry.path().canonicalize()?, entry.file_name())?;
-                }
+    let report = opts.walk.walk(
+        opts.walk.walker(&tx).detect_moves(opts.detect_moves),
+        proc,
+        opts.label.as_deref(),
+        Some(tar),
+    )?;
+    tx.commit()?;
+    println!("session_id {}", report.session_id);
+    Ok(())
+}
+
+fn status(opts: &StatusOpts) -> Result<()> {
+    let cache = open_cache_read_only(&opts.cache_db)?;
+    let stdout = std::io::stdout();
+    let proc = ChangeNotifier::new(StatusPrinter(stdout.lock()));
+    opts.walk
+        .walk(opts.walk.walker(&cache).dry_run(true), proc, None, None)?;
+    Ok(())
+}
+
+fn sessions(opts: &SessionsOpts) -> Result<()> {
+    let cache = open_cache_read_only(&opts.cache_db)?;
+    for session in list_sessions(&cache)? {
+        println!("session {}", session.session_id);
+        if !session.namespace.is_empty() {
+            println!("  namespace: {}", session.namespace);
+        }
+        println!("  started: {}", format_time(session.started_at));
+        if let Some(ended_at) = session.ended_at {
+            println!("  ended: {}", format_time(ended_at));
+        }
+        let fields = [
+            ("host", &session.hostname),
+            ("label", &session.label),
+            ("processor", &session.processor),
+            ("artifact", &session.artifact),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                println!("  {}: {}", name, value);
             }
         }
+        for (path, mount_path) in &session.roots {
+            println!("  root: {} -> {}", path.display(), mount_path.display());
+        }
     }
-    let (_walker, session_id) = adder.finish_processing()?;
-    tx.commit()?;
-    println!("session_id {}", session_id);
     Ok(())
 }
 
+/// `YYYY-MM-DD hh:mm:ss` in UTC
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// print one line per change, like `git status --short`: `A path`, `M path`, `D path`, `R from -> path`,
+/// or `P path` when only the mode, the owner or the ctime changed
+struct StatusPrinter<W: Write>(W);
+
+impl<W: Write> ChangeSink for StatusPrinter<W> {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        let suffix = match event.kind {
+            FsNodeType::File => "",
+            FsNodeType::Symlink => "@",
+            FsNodeType::Folder => "/",
+            FsNodeType::Special(_) => "|",
+        };
+        let status = match event.action {
+            ChangeAction::Added => 'A',
+            ChangeAction::Changed => 'M',
+            ChangeAction::MetadataChanged => 'P',
+            ChangeAction::Removed => 'D',
+            ChangeAction::Moved {
+                from_mount_path, ..
+            } => {
+                writeln!(
+                    self.0,
+                    "R {}{} -> {}{}",
+                    from_mount_path.display(),
+                    suffix,
+                    event.mount_path.display(),
+                    suffix
+                )?;
+                return Ok(());
+            }
+        };
+        writeln!(
+            self.0,
+            "{} {}{}",
+            status,
+            event.mount_path.display(),
+            suffix
+        )?;
+        Ok(())
+    }
+}
+
+fn print_report(report: &SessionReport) {
+    let changes = &report.changes;
+    for (name, counts) in [
+        ("files", &changes.files),
+        ("symlinks", &changes.symlinks),
+        ("folders", &changes.folders),
+        ("specials", &changes.specials),
+    ] {
+        eprintln!(
+            "{}: {} added, {} changed, {} removed, {} moved, {} unchanged",
+            name, counts.added, counts.changed, counts.removed, counts.moved, counts.unchanged
+        );
+    }
+    eprintln!(
+        "{} bytes read, {} cache hits, {} cache misses, {:.3}s",
+        report.bytes_read,
+        report.cache_hits,
+        report.cache_misses,
+        report.elapsed.as_secs_f64()
+    );
+}
+
+/// open or create the cache, updating its schema if needed
+fn open_cache(path: &Path) -> Result<Connection> {
+    let db = Connection::open(path)?;
+    setup_sqlite_cache(&db)?;
+    Ok(db)
+}
+
+/// open an existing cache without writing to it, its schema has to be up to date
+fn open_cache_read_only(path: &Path) -> Result<Connection> {
+    if !path.exists() {
+        bail!("the cache {} does not exist", path.display());
+    }
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = sqlite_schema_version(&db)?;
+    if version < SQLITE_SCHEMA_VERSION {
+        bail!(
+            "the cache {} has the schema version {} instead of {}, a run archiving the input paths upgrades it",
+            path.display(),
+            version,
+            SQLITE_SCHEMA_VERSION
+        );
+    }
+    if version > SQLITE_SCHEMA_VERSION {
+        bail!(
+            "the cache {} has the schema version {}, it was written by a more recent version of sausage",
+            path.display(),
+            version
+        );
+    }
+    Ok(db)
+}
+
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
-    let cache = if opts.cache_db.exists() {
-        Connection::open(&opts.cache_db)?
-    } else {
-        let db = Connection::open(&opts.cache_db)?;
-        setup_sqlite_cache(&db)?;
-        db
-    };
-    let tar_file = File::create(&opts.output_tar)?;
+    match &opts.command {
+        Some(Command::Status(status_opts)) => return status(status_opts),
+        Some(Command::Sessions(sessions_opts)) => return sessions(sessions_opts),
+        None => {}
+    }
+    // both are required when no sub command is given
+    let cache = open_cache(opts.cache_db.as_ref().unwrap())?;
+    let output_tar = opts.output_tar.as_ref().unwrap();
+    let tar_file = File::create(output_tar)?;
+    let tar = output_tar.canonicalize()?;
 
     if opts.compress {
         let enc = GzEncoder::new(tar_file, Compression::new(opts.compress_level));
-        run(enc, cache, &opts)
+        run(enc, cache, &opts, &tar)
     } else {
-        run(tar_file, cache, &opts)
+        run(tar_file, cache, &opts, &tar)
     }
 }
@@ -0,0 +1,111 @@
+use std::{
+    fs::Metadata,
+    io::{self, Read},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::Result;
+
+use crate::vfs::{Vfs, VfsFileType, VfsMetadata};
+
+/// File system metadata stored along a cached item, used to detect if an entry changed
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FsStat {
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub size: u64,
+    pub inode: u64,
+    /// device holding the entry, with `inode` it identifies the entry whatever its path
+    pub device: u64,
+    /// only computed for files, when using `ChangeDetector::ContentHash`
+    pub content_hash: Option<Vec<u8>>,
+    /// the entry was modified too close to the scan to be trusted, it is always considered changed
+    /// by the next session (the "racily clean" problem of git)
+    pub racy: bool,
+}
+
+impl FsStat {
+    pub fn from_metadata(meta: &Metadata) -> Result<Self> {
+        Ok(Self::from_vfs_metadata(&VfsMetadata::from_metadata(meta)?))
+    }
+
+    pub fn from_vfs_metadata(meta: &VfsMetadata) -> Self {
+        Self {
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+            size: meta.size,
+            inode: meta.inode,
+            device: meta.device,
+            content_hash: None,
+            racy: false,
+        }
+    }
+
+    /// flag the entry as racy if it was modified or its status changed after `threshold`
+    pub fn check_racy(&mut self, threshold: SystemTime) {
+        self.racy = self.mtime >= threshold || self.ctime >= threshold;
+    }
+}
+
+/// Policy used to decide if a cached entry is still up to date, from the fastest to the most accurate
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChangeDetector {
+    /// same modification time
+    #[default]
+    Mtime,
+    /// same size and modification time
+    SizeMtime,
+    /// same inode, status change time and size, catches edits that restore the mtime
+    InodeCtimeSize,
+    /// same content hash for files, every file is read at each session; same mtime for other entries
+    ContentHash,
+}
+
+impl ChangeDetector {
+    pub fn is_unchanged(&self, cached: &FsStat, current: &FsStat) -> bool {
+        if cached.racy {
+            return false;
+        }
+        match self {
+            ChangeDetector::Mtime => cached.mtime == current.mtime,
+            ChangeDetector::SizeMtime => {
+                cached.size == current.size && cached.mtime == current.mtime
+            }
+            ChangeDetector::InodeCtimeSize => {
+                cached.inode == current.inode
+                    && cached.ctime == current.ctime
+                    && cached.size == current.size
+            }
+            ChangeDetector::ContentHash => match (&cached.content_hash, &current.content_hash) {
+                (Some(cached_hash), Some(current_hash)) => cached_hash == current_hash,
+                (None, None) => cached.mtime == current.mtime,
+                _ => false,
+            },
+        }
+    }
+
+    /// gather the metadata needed by this policy, `meta` being the result of `symlink_metadata`
+    pub(crate) fn stat(&self, vfs: &dyn Vfs, path: &Path, meta: &VfsMetadata) -> Result<FsStat> {
+        let mut stat = FsStat::from_vfs_metadata(meta);
+        if *self == ChangeDetector::ContentHash && meta.file_type == VfsFileType::File {
+            stat.content_hash = Some(hash_file(vfs, path)?);
+        }
+        Ok(stat)
+    }
+}
+
+fn hash_file(vfs: &dyn Vfs, path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = vfs.open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().as_bytes().to_vec())
+}
@@ -0,0 +1,267 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{Sender, SyncSender},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{FsChangeWatcher, FsNodeType, NodeMeta, SpecialKind};
+
+/// What happened to an entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeAction {
+    Added,
+    Changed,
+    /// only the mode, the owner or the ctime changed, see `FsChangeWatcher::notify_metadata_changed`
+    MetadataChanged,
+    /// the entry is gone, its descendants are removed first
+    Removed,
+    /// the entry was at `from`, see `MemoizedFsWalker::detect_moves`
+    Moved {
+        from: PathBuf,
+        from_mount_path: PathBuf,
+    },
+}
+
+/// One change found by `ChangeNotifier`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub kind: FsNodeType,
+    pub action: ChangeAction,
+    pub path: PathBuf,
+    pub mount_path: PathBuf,
+    /// current metadata of the entry, the cached one for removed and moved entries.
+    /// `None` when the change went through `FsChangeWatcher` methods, which do not carry it,
+    /// and for removed or moved entries cached before the metadata was tracked
+    pub meta: Option<NodeMeta>,
+}
+
+/// Receiver of the changes found by `ChangeNotifier`, a single method alternative to `FsChangeWatcher`.
+/// Every `FsChangeWatcher` is a `ChangeSink`, and `SinkWatcher` turns a sink into a watcher
+pub trait ChangeSink {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()>;
+}
+
+impl<W: FsChangeWatcher> ChangeSink for W {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        let ChangeEvent {
+            kind,
+            action,
+            path,
+            mount_path,
+            ..
+        } = event;
+        let (path, mount_path) = (path.as_path(), mount_path.as_path());
+        match action {
+            ChangeAction::Added => match kind {
+                FsNodeType::File => self.notify_file_added(path, mount_path),
+                FsNodeType::Symlink => self.notify_symlink_added(path, mount_path),
+                FsNodeType::Folder => self.notify_folder_added(path, mount_path),
+                FsNodeType::Special(kind) => self.notify_special_added(path, mount_path, kind),
+            },
+            ChangeAction::Changed => match kind {
+                FsNodeType::File => self.notify_file_changed(path, mount_path),
+                FsNodeType::Symlink => self.notify_symlink_changed(path, mount_path),
+                FsNodeType::Folder => self.notify_folder_changed(path, mount_path),
+                FsNodeType::Special(kind) => self.notify_special_changed(path, mount_path, kind),
+            },
+            ChangeAction::MetadataChanged => self.notify_metadata_changed(path, mount_path, kind),
+            ChangeAction::Removed => match kind {
+                FsNodeType::File => self.notify_file_removed(path, mount_path),
+                FsNodeType::Symlink => self.notify_symlink_removed(path, mount_path),
+                FsNodeType::Folder => self.notify_folder_removed(path, mount_path),
+                FsNodeType::Special(kind) => self.notify_special_removed(path, mount_path, kind),
+            },
+            ChangeAction::Moved {
+                from,
+                from_mount_path,
+            } => {
+                let from = (from.as_path(), from_mount_path.as_path());
+                match kind {
+                    FsNodeType::File => self.notify_file_moved(from.0, from.1, path, mount_path),
+                    FsNodeType::Symlink => {
+                        self.notify_symlink_moved(from.0, from.1, path, mount_path)
+                    }
+                    FsNodeType::Folder => {
+                        self.notify_folder_moved(from.0, from.1, path, mount_path)
+                    }
+                    FsNodeType::Special(kind) => {
+                        self.notify_special_moved(from.0, from.1, path, mount_path, kind)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// the changes are consumed by the receiver, possibly from another thread while the walk is running.
+/// Sending fails once the receiver is dropped
+impl ChangeSink for Sender<ChangeEvent> {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        self.send(event)
+            .map_err(|_| anyhow!("the receiver of the changes is gone"))
+    }
+}
+
+/// same as `Sender`, blocking while the channel is full
+impl ChangeSink for SyncSender<ChangeEvent> {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        self.send(event)
+            .map_err(|_| anyhow!("the receiver of the changes is gone"))
+    }
+}
+
+/// `FsChangeWatcher` handing each notification to a `ChangeSink` as a `ChangeEvent` without metadata
+pub struct SinkWatcher<S: ChangeSink>(pub S);
+
+impl<S: ChangeSink> SinkWatcher<S> {
+    fn send(
+        &mut self,
+        kind: FsNodeType,
+        action: ChangeAction,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.0.send_change(ChangeEvent {
+            kind,
+            action,
+            path: path.to_path_buf(),
+            mount_path: mount_path.to_path_buf(),
+            meta: None,
+        })
+    }
+
+    fn send_moved(
+        &mut self,
+        kind: FsNodeType,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        let action = ChangeAction::Moved {
+            from: from.to_path_buf(),
+            from_mount_path: from_mount_path.to_path_buf(),
+        };
+        self.send(kind, action, path, mount_path)
+    }
+}
+
+impl<S: ChangeSink> FsChangeWatcher for SinkWatcher<S> {
+    fn notify_file_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::File, ChangeAction::Added, path, mount_path)
+    }
+
+    fn notify_file_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::File, ChangeAction::Changed, path, mount_path)
+    }
+
+    fn notify_file_removed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::File, ChangeAction::Removed, path, mount_path)
+    }
+
+    fn notify_symlink_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Symlink, ChangeAction::Added, path, mount_path)
+    }
+
+    fn notify_symlink_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Symlink, ChangeAction::Changed, path, mount_path)
+    }
+
+    fn notify_symlink_removed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Symlink, ChangeAction::Removed, path, mount_path)
+    }
+
+    fn notify_folder_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Folder, ChangeAction::Added, path, mount_path)
+    }
+
+    fn notify_folder_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Folder, ChangeAction::Changed, path, mount_path)
+    }
+
+    fn notify_folder_removed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.send(FsNodeType::Folder, ChangeAction::Removed, path, mount_path)
+    }
+
+    fn notify_special_added(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        let node_type = FsNodeType::Special(kind);
+        self.send(node_type, ChangeAction::Added, path, mount_path)
+    }
+
+    fn notify_special_changed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        let node_type = FsNodeType::Special(kind);
+        self.send(node_type, ChangeAction::Changed, path, mount_path)
+    }
+
+    fn notify_special_removed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        let node_type = FsNodeType::Special(kind);
+        self.send(node_type, ChangeAction::Removed, path, mount_path)
+    }
+
+    fn notify_metadata_changed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        node_type: FsNodeType,
+    ) -> Result<()> {
+        self.send(node_type, ChangeAction::MetadataChanged, path, mount_path)
+    }
+
+    fn notify_file_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.send_moved(FsNodeType::File, from, from_mount_path, path, mount_path)
+    }
+
+    fn notify_symlink_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.send_moved(FsNodeType::Symlink, from, from_mount_path, path, mount_path)
+    }
+
+    fn notify_folder_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.send_moved(FsNodeType::Folder, from, from_mount_path, path, mount_path)
+    }
+
+    fn notify_special_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        let node_type = FsNodeType::Special(kind);
+        self.send_moved(node_type, from, from_mount_path, path, mount_path)
+    }
+}
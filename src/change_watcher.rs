@@ -1,13 +1,19 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
 };
 
 use anyhow::Result;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{FsEntry, FsProcessor};
+use crate::{
+    change_event::{ChangeAction, ChangeEvent, ChangeSink},
+    vfs::{StdFs, Vfs, VfsMetadata},
+    ChangeCounts, EntryCounts, FsEntry, FsProcessor, SessionReport, SpecialKind,
+};
 
 pub trait FsChangeWatcher {
     fn notify_file_added(&mut self, path: &Path, mount_path: &Path) -> Result<()>;
@@ -21,43 +27,259 @@ pub trait FsChangeWatcher {
     fn notify_folder_added(&mut self, path: &Path, mount_path: &Path) -> Result<()>;
     fn notify_folder_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()>;
     fn notify_folder_removed(&mut self, path: &Path, mount_path: &Path) -> Result<()>;
+
+    fn notify_special_added(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()>;
+    fn notify_special_changed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()>;
+    fn notify_special_removed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()>;
+
+    /// only the mode, the owner or the ctime of the entry changed, its content is the same, see `NodeMeta`.
+    /// Such changes are only seen by change detectors looking at the ctime, like `ChangeDetector::InodeCtimeSize`.
+    /// A file whose ctime alone changed, after an extended attribute change or a rewrite restoring its mtime,
+    /// is notified as changed instead. Notified like any other change by default
+    fn notify_metadata_changed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        node_type: FsNodeType,
+    ) -> Result<()> {
+        match node_type {
+            FsNodeType::File => self.notify_file_changed(path, mount_path),
+            FsNodeType::Symlink => self.notify_symlink_changed(path, mount_path),
+            FsNodeType::Folder => self.notify_folder_changed(path, mount_path),
+            FsNodeType::Special(kind) => self.notify_special_changed(path, mount_path, kind),
+        }
+    }
+
+    /// the entry of `from` is now at `path`, see `MemoizedFsWalker::detect_moves`.
+    /// The entries below a moved folder moved along, they are only notified if they changed
+    fn notify_file_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()>;
+    fn notify_symlink_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()>;
+    fn notify_folder_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+    ) -> Result<()>;
+    fn notify_special_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()>;
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+/// The metadata is `None` for the entries cached before it was tracked, they are always notified as changed
 pub enum FsNode {
-    File,
-    Symlink,
-    Folder(HashMap<PathBuf, FsNodeType>),
+    File(Option<NodeMeta>),
+    Symlink(Option<NodeMeta>),
+    /// the removed children of a folder are found by the cache, which lists the entries below it
+    Folder(Option<NodeMeta>),
+    Special(SpecialKind, Option<NodeMeta>),
+}
+
+/// Metadata of an entry when it was last notified, to tell metadata-only changes from content changes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeMeta {
+    /// permission bits, without the file type
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// time of the last status change, also changed by extended attributes
+    pub ctime: SystemTime,
+    pub mtime: SystemTime,
+    pub size: u64,
+}
+
+impl NodeMeta {
+    pub fn from_vfs_metadata(meta: &VfsMetadata) -> Self {
+        Self {
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            ctime: meta.ctime,
+            mtime: meta.mtime,
+            size: meta.size,
+        }
+    }
+
+    /// true if the entry kept its mtime and size since `previous`, but not its mode, its owner or its ctime.
+    /// The ctime of a file alone does not make it a metadata change, as it also changes when
+    /// the file is rewritten with its mtime restored
+    fn is_metadata_change(&self, previous: &NodeMeta, node_type: &FsNodeType) -> bool {
+        if (self.mtime, self.size) != (previous.mtime, previous.size) {
+            return false;
+        }
+        let owner = (self.mode, self.uid, self.gid);
+        owner != (previous.mode, previous.uid, previous.gid)
+            || (self.ctime != previous.ctime && !matches!(node_type, FsNodeType::File))
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FsNodeType {
     File,
     Symlink,
     Folder,
+    Special(SpecialKind),
 }
 
 impl FsNode {
     fn node_type(&self) -> FsNodeType {
         match self {
-            FsNode::File => FsNodeType::File,
-            FsNode::Symlink => FsNodeType::Symlink,
-            FsNode::Folder(_) => FsNodeType::Folder,
+            FsNode::File(_) => FsNodeType::File,
+            FsNode::Symlink(_) => FsNodeType::Symlink,
+            FsNode::Folder(..) => FsNodeType::Folder,
+            FsNode::Special(kind, _) => FsNodeType::Special(*kind),
+        }
+    }
+
+    /// metadata of the entry when it was last notified
+    pub fn meta(&self) -> Option<&NodeMeta> {
+        match self {
+            FsNode::File(meta)
+            | FsNode::Symlink(meta)
+            | FsNode::Folder(meta)
+            | FsNode::Special(_, meta) => meta.as_ref(),
         }
     }
 }
 
-pub struct ChangeNotifier<W: FsChangeWatcher> {
-    watcher: W,
+impl FsNodeType {
+    fn counts<'a>(&self, changes: &'a mut ChangeCounts) -> &'a mut EntryCounts {
+        match self {
+            FsNodeType::File => &mut changes.files,
+            FsNodeType::Symlink => &mut changes.symlinks,
+            FsNodeType::Folder => &mut changes.folders,
+            FsNodeType::Special(_) => &mut changes.specials,
+        }
+    }
+}
+
+/// Processor notifying a `ChangeSink`, like any `FsChangeWatcher`, of the changes of the walked entries
+pub struct ChangeNotifier<S: ChangeSink> {
+    sink: S,
+    /// notifications sent so far, unchanged entries are counted by the walker
+    changes: ChangeCounts,
+    vfs: Arc<dyn Vfs>,
 }
 
-impl<W: FsChangeWatcher> ChangeNotifier<W> {
-    pub fn new(watcher: W) -> Self {
-        Self { watcher }
+impl<S: ChangeSink> ChangeNotifier<S> {
+    pub fn new(sink: S) -> Self {
+        Self::with_vfs(sink, Arc::new(StdFs))
+    }
+
+    /// Read the metadata of the entries from `vfs`, which has to be the one walked by the session
+    pub fn with_vfs(sink: S, vfs: Arc<dyn Vfs>) -> Self {
+        Self {
+            sink,
+            changes: ChangeCounts::default(),
+            vfs,
+        }
+    }
+
+    /// the sink receiving the changes
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    fn read_meta(&self, path: &Path) -> Result<NodeMeta> {
+        Ok(NodeMeta::from_vfs_metadata(
+            &self.vfs.symlink_metadata(path)?,
+        ))
+    }
+
+    /// count the change and hand it to the sink
+    fn send(
+        &mut self,
+        kind: FsNodeType,
+        action: ChangeAction,
+        path: &Path,
+        mount_path: &Path,
+        meta: Option<NodeMeta>,
+    ) -> Result<()> {
+        let counts = kind.counts(&mut self.changes);
+        match action {
+            ChangeAction::Added => counts.added += 1,
+            ChangeAction::Changed | ChangeAction::MetadataChanged => counts.changed += 1,
+            ChangeAction::Removed => counts.removed += 1,
+            ChangeAction::Moved { .. } => counts.moved += 1,
+        }
+        self.sink.send_change(ChangeEvent {
+            kind,
+            action,
+            path: path.to_path_buf(),
+            mount_path: mount_path.to_path_buf(),
+            meta,
+        })
+    }
+
+    /// notify an entry processed again, `previous` being its cached item
+    fn notify_processed(
+        &mut self,
+        kind: FsNodeType,
+        path: &Path,
+        mount_path: &Path,
+        previous: Option<FsNode>,
+        meta: &NodeMeta,
+    ) -> Result<()> {
+        let action = match previous {
+            Some(previous) if previous.node_type() == kind => match previous.meta() {
+                Some(previous) if meta.is_metadata_change(previous, &kind) => {
+                    ChangeAction::MetadataChanged
+                }
+                _ => ChangeAction::Changed,
+            },
+            Some(previous) => {
+                // another kind of entry replaced it
+                let previous_meta = previous.meta().cloned();
+                let removed = ChangeAction::Removed;
+                self.send(
+                    previous.node_type(),
+                    removed,
+                    path,
+                    mount_path,
+                    previous_meta,
+                )?;
+                ChangeAction::Added
+            }
+            None => ChangeAction::Added,
+        };
+        self.send(kind, action, path, mount_path, Some(meta.clone()))
     }
 }
 
-impl<W: FsChangeWatcher> FsProcessor for ChangeNotifier<W> {
+impl<S: ChangeSink> FsProcessor for ChangeNotifier<S> {
     type Item = FsNode;
 
     fn process_file(
@@ -66,23 +288,9 @@ impl<W: FsChangeWatcher> FsProcessor for ChangeNotifier<W> {
         mount_path: &Path,
         previous: Option<Self::Item>,
     ) -> Result<Self::Item> {
-        match previous {
-            Some(FsNode::File) => {
-                self.watcher.notify_file_changed(path, mount_path)?;
-            }
-            Some(FsNode::Symlink) => {
-                self.watcher.notify_symlink_removed(path, mount_path)?;
-                self.watcher.notify_file_added(path, mount_path)?;
-            }
-            Some(FsNode::Folder(_)) => {
-                self.watcher.notify_folder_removed(path, mount_path)?;
-                self.watcher.notify_file_added(path, mount_path)?;
-            }
-            None => {
-                self.watcher.notify_file_added(path, mount_path)?;
-            }
-        }
-        Ok(FsNode::File)
+        let meta = self.read_meta(path)?;
+        self.notify_processed(FsNodeType::File, path, mount_path, previous, &meta)?;
+        Ok(FsNode::File(Some(meta)))
     }
 
     fn process_symlink(
@@ -91,74 +299,74 @@ impl<W: FsChangeWatcher> FsProcessor for ChangeNotifier<W> {
         mount_path: &Path,
         previous: Option<Self::Item>,
     ) -> Result<Self::Item> {
-        match previous {
-            Some(FsNode::File) => {
-                self.watcher.notify_file_removed(path, mount_path)?;
-                self.watcher.notify_symlink_added(path, mount_path)?;
-            }
-            Some(FsNode::Symlink) => {
-                self.watcher.notify_symlink_changed(path, mount_path)?;
-            }
-            Some(FsNode::Folder(_)) => {
-                self.watcher.notify_folder_removed(path, mount_path)?;
-                self.watcher.notify_symlink_added(path, mount_path)?;
-            }
-            None => {
-                self.watcher.notify_symlink_added(path, mount_path)?;
-            }
-        }
-        Ok(FsNode::Symlink)
+        let meta = self.read_meta(path)?;
+        self.notify_processed(FsNodeType::Symlink, path, mount_path, previous, &meta)?;
+        Ok(FsNode::Symlink(Some(meta)))
+    }
+
+    fn process_special(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        let meta = self.read_meta(path)?;
+        let node_type = FsNodeType::Special(kind);
+        self.notify_processed(node_type, path, mount_path, previous, &meta)?;
+        Ok(FsNode::Special(kind, Some(meta)))
     }
 
     fn process_folder(
         &mut self,
         path: &Path,
         mount_path: &Path,
-        sub: HashMap<PathBuf, FsEntry<Self::Item>>,
+        _sub: HashMap<PathBuf, FsEntry<Self::Item>>,
         previous: Option<Self::Item>,
     ) -> Result<Self::Item> {
-        let new_sub: HashMap<_, _> = sub
-            .into_iter()
-            .map(|(k, v)| (k, v.item.node_type()))
-            .collect();
-        match previous {
-            Some(FsNode::File) => {
-                self.watcher.notify_file_removed(path, mount_path)?;
-                self.watcher.notify_folder_added(path, mount_path)?;
-            }
-            Some(FsNode::Symlink) => {
-                self.watcher.notify_symlink_removed(path, mount_path)?;
-                self.watcher.notify_folder_added(path, mount_path)?;
-            }
-            Some(FsNode::Folder(old_sub)) => {
-                let sub_keys: HashSet<_> = new_sub.keys().collect();
-                let old_sub_keys: HashSet<_> = old_sub.keys().collect();
-                let removed_subs = old_sub_keys.difference(&sub_keys);
-                for sub_path in removed_subs {
-                    let note_type = old_sub.get(*sub_path).unwrap();
-                    let full_sub_path = path.join(sub_path);
-                    let new_mount_path = mount_path.join(sub_path);
-                    match note_type {
-                        FsNodeType::File => {
-                            self.watcher
-                                .notify_file_removed(&full_sub_path, &new_mount_path)?;
-                        }
-                        FsNodeType::Symlink => {
-                            self.watcher
-                                .notify_symlink_removed(&full_sub_path, &new_mount_path)?;
-                        }
-                        FsNodeType::Folder => {
-                            self.watcher
-                                .notify_folder_removed(&full_sub_path, &new_mount_path)?;
-                        }
-                    }
-                }
-                self.watcher.notify_folder_changed(path, mount_path)?;
-            }
-            None => {
-                self.watcher.notify_folder_added(path, mount_path)?;
-            }
+        let meta = self.read_meta(path)?;
+        self.notify_processed(FsNodeType::Folder, path, mount_path, previous, &meta)?;
+        Ok(FsNode::Folder(Some(meta)))
+    }
+
+    fn process_removed(&mut self, path: &Path, mount_path: &Path, item: Self::Item) -> Result<()> {
+        let node_type = item.node_type();
+        let meta = item.meta().cloned();
+        self.send(node_type, ChangeAction::Removed, path, mount_path, meta)
+    }
+
+    fn process_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+        item: &Self::Item,
+    ) -> Result<()> {
+        let action = ChangeAction::Moved {
+            from: from.to_path_buf(),
+            from_mount_path: from_mount_path.to_path_buf(),
+        };
+        let meta = item.meta().cloned();
+        self.send(item.node_type(), action, path, mount_path, meta)
+    }
+
+    fn kind(&self) -> &'static str {
+        "change_notifier"
+    }
+
+    fn fill_report(&self, report: &mut SessionReport) {
+        let notified = [
+            (&mut report.changes.files, &self.changes.files),
+            (&mut report.changes.symlinks, &self.changes.symlinks),
+            (&mut report.changes.folders, &self.changes.folders),
+            (&mut report.changes.specials, &self.changes.specials),
+        ];
+        for (counts, notified) in notified {
+            counts.added = notified.added;
+            counts.changed = notified.changed;
+            counts.removed = notified.removed;
+            counts.moved = notified.moved;
         }
-        Ok(FsNode::Folder(new_sub))
     }
 }
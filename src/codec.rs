@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Encoding of the processor items saved by the sqlite and redb caches,
+/// chosen once per database as every entry must be read back with it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ItemCodec {
+    /// compact and fast, the default
+    #[cfg_attr(not(feature = "sqlite_debug"), default)]
+    Bincode,
+    /// readable when inspecting the database, the default with the `sqlite_debug` feature
+    #[cfg(feature = "json")]
+    #[cfg_attr(feature = "sqlite_debug", default)]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl ItemCodec {
+    /// name saved in the database
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemCodec::Bincode => "bincode",
+            #[cfg(feature = "json")]
+            ItemCodec::Json => "json",
+            #[cfg(feature = "cbor")]
+            ItemCodec::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "bincode" => Ok(ItemCodec::Bincode),
+            #[cfg(feature = "json")]
+            "json" => Ok(ItemCodec::Json),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(ItemCodec::Cbor),
+            #[cfg(not(feature = "json"))]
+            "json" => Err(anyhow!(
+                "the items are encoded with json, build with the json feature to read them"
+            )),
+            #[cfg(not(feature = "cbor"))]
+            "cbor" => Err(anyhow!(
+                "the items are encoded with cbor, build with the cbor feature to read them"
+            )),
+            _ => Err(anyhow!("unknown item codec {}", name)),
+        }
+    }
+
+    pub fn encode<I: Serialize>(&self, item: &I) -> Result<Vec<u8>> {
+        match self {
+            ItemCodec::Bincode => Ok(bincode::serialize(item)?),
+            #[cfg(feature = "json")]
+            ItemCodec::Json => Ok(serde_json::to_vec(item)?),
+            #[cfg(feature = "cbor")]
+            ItemCodec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(item, &mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<I: DeserializeOwned>(&self, bytes: &[u8]) -> Result<I> {
+        match self {
+            ItemCodec::Bincode => Ok(bincode::deserialize(bytes)?),
+            #[cfg(feature = "json")]
+            ItemCodec::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "cbor")]
+            ItemCodec::Cbor => Ok(ciborium::from_reader(bytes)?),
+        }
+    }
+}
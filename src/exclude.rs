@@ -0,0 +1,123 @@
+use std::{
+    ffi::OsString,
+    io::{ErrorKind, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+use crate::vfs::Vfs;
+
+/// Rules deciding which entries are skipped by the walker, with the `.gitignore` semantics:
+/// the last matching rule wins, rules from a deeper folder win over the ones of its parents
+/// and a rule starting with `!` includes back what a previous rule excluded.
+/// An excluded folder is never read, so nothing under it can be included back.
+#[derive(Clone, Default)]
+pub struct ExcludeRules {
+    patterns: Vec<String>,
+    ignore_files: Vec<OsString>,
+}
+
+impl ExcludeRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rules read from `.gitignore` and `.sausageignore` files found while walking
+    pub fn gitignore() -> Self {
+        Self::new()
+            .add_ignore_file(".gitignore")
+            .add_ignore_file(".sausageignore")
+    }
+
+    /// Add a rule using the `.gitignore` syntax, relative to the path given to `add_path`.
+    /// These rules have a lower priority than the ones read from ignore files
+    pub fn add_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.patterns.push(pattern.into());
+        self
+    }
+
+    /// Read rules from the files with this name in every visited folder
+    pub fn add_ignore_file(mut self, file_name: impl Into<OsString>) -> Self {
+        self.ignore_files.push(file_name.into());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.ignore_files.is_empty()
+    }
+}
+
+/// Rules that apply to the entries of a folder, from the deepest folder to the root
+#[derive(Clone)]
+pub(crate) struct PathFilter {
+    rules: Arc<ExcludeRules>,
+    matchers: Vec<Arc<Gitignore>>,
+}
+
+impl PathFilter {
+    /// filter for the entries under `root`
+    pub(crate) fn new(rules: &ExcludeRules, root: &Path) -> Result<Self> {
+        let mut matchers = Vec::new();
+        if !rules.patterns.is_empty() {
+            let mut builder = GitignoreBuilder::new(root);
+            for pattern in &rules.patterns {
+                builder.add_line(None, pattern)?;
+            }
+            matchers.push(Arc::new(builder.build()?));
+        }
+        Ok(Self {
+            rules: Arc::new(rules.clone()),
+            matchers,
+        })
+    }
+
+    /// filter for the entries of a sub folder, reading its ignore files
+    pub(crate) fn enter_folder(&self, vfs: &dyn Vfs, path: &Path) -> Result<Self> {
+        let mut builder = None;
+        for name in &self.rules.ignore_files {
+            let file = path.join(name);
+            match vfs.symlink_metadata(&file) {
+                Ok(meta) if !meta.file_type.is_dir() => {}
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => continue,
+            }
+            // dangling symlinks are skipped like missing files
+            let mut reader = match vfs.open(&file) {
+                Ok(reader) => reader,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            // like git, an ignore file is not required to be valid utf-8
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content)?;
+            let builder = builder.get_or_insert_with(|| GitignoreBuilder::new(path));
+            for line in String::from_utf8_lossy(&content).lines() {
+                builder.add_line(Some(file.clone()), line)?;
+            }
+        }
+        let builder = match builder {
+            Some(builder) => builder,
+            None => return Ok(self.clone()),
+        };
+        let mut filter = self.clone();
+        filter.matchers.push(Arc::new(builder.build()?));
+        Ok(filter)
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
@@ -1,10 +1,48 @@
 mod memoized;
-pub use memoized::{MemoizedFsCache, MemoizedFsCacheSession, MemoizedFsWalker};
+pub use memoized::{
+    CacheLookup, MemoizedFsCache, MemoizedFsCacheSession, MemoizedFsWalker,
+    MemoizedFsWalkerSession, WalkError,
+};
 
 #[cfg(feature = "sqlite")]
 mod sqlite;
 #[cfg(feature = "sqlite")]
-pub use sqlite::{rollback_before_session_id, setup_sqlite_cache};
+pub use sqlite::{
+    list_sessions, prune_history_before_session_id, rollback_before_session_id,
+    set_sqlite_item_codec, setup_sqlite_cache, sqlite_item_codec, sqlite_schema_version,
+    SQLITE_SCHEMA_VERSION,
+};
+
+#[cfg(any(feature = "sqlite", feature = "redb"))]
+mod codec;
+#[cfg(any(feature = "sqlite", feature = "redb"))]
+pub use codec::ItemCodec;
+
+#[cfg(feature = "redb")]
+mod redb;
+#[cfg(feature = "redb")]
+pub use crate::redb::{RedbCache, RedbSession};
+
+mod memory;
+pub use memory::{MemoryCache, MemoryCacheSession};
+
+mod change_detector;
+pub use change_detector::{ChangeDetector, FsStat};
+
+mod exclude;
+pub use exclude::ExcludeRules;
+
+#[cfg(any(feature = "sqlite", feature = "serde"))]
+mod path_bytes;
+
+mod vfs;
+pub use vfs::{MemoryFs, StdFs, Vfs, VfsDirEntry, VfsFileType, VfsMetadata, VfsOp};
+
+mod report;
+pub use report::{ChangeCounts, EntryCounts, SessionReport};
+
+mod session;
+pub use session::SessionInfo;
 
 //mod ipfs;
 
@@ -16,10 +54,16 @@ pub use tar::TarProcessor;
 #[cfg(feature = "change_watcher")]
 mod change_watcher;
 #[cfg(feature = "change_watcher")]
-pub use change_watcher::{ChangeNotifier, FsChangeWatcher};
+pub use change_watcher::{ChangeNotifier, FsChangeWatcher, FsNode, FsNodeType, NodeMeta};
+
+#[cfg(feature = "change_watcher")]
+mod change_event;
+#[cfg(feature = "change_watcher")]
+pub use change_event::{ChangeAction, ChangeEvent, ChangeSink, SinkWatcher};
 
 use std::{
     collections::HashMap,
+    fs::FileType,
     path::{Path, PathBuf},
     time::SystemTime,
 };
@@ -31,10 +75,42 @@ pub struct FsEntry<T> {
     pub mtime: SystemTime,
 }
 
+/// File system entries that are neither a file, a folder nor a symlink
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl SpecialKind {
+    #[cfg(unix)]
+    pub fn from_file_type(ft: &FileType) -> Option<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        if ft.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if ft.is_socket() {
+            Some(SpecialKind::Socket)
+        } else if ft.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else if ft.is_char_device() {
+            Some(SpecialKind::CharDevice)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_file_type(_ft: &FileType) -> Option<Self> {
+        None
+    }
+}
+
 /// File system processor implementation interface
 pub trait FsProcessor {
     /// items yelded after processing a file system entry can be a hash, nothing or something hard to process
-
     type Item;
 
     /// process a file, return an item, this item will be cached in the `MemoizedFsWalker` database
@@ -53,7 +129,16 @@ pub trait FsProcessor {
         previous: Option<Self::Item>,
     ) -> Result<Self::Item>;
 
-    /// process a symlink, return an item, this item will be cached in the `MemoizedFsWalker` database
+    /// process a fifo, a socket or a device, return an item, this item will be cached in the `MemoizedFsWalker` database
+    fn process_special(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item>;
+
+    /// process a folder, return an item, this item will be cached in the `MemoizedFsWalker` database
     fn process_folder(
         &mut self,
         path: &Path,
@@ -61,4 +146,56 @@ pub trait FsProcessor {
         sub: HashMap<PathBuf, FsEntry<Self::Item>>,
         previous: Option<Self::Item>,
     ) -> Result<Self::Item>;
+
+    /// an entry cached by a previous session is gone, `item` being its cached item. Every entry of a removed
+    /// subtree is handed, each folder after its descendants, once their parent folder was processed again.
+    /// With `MemoizedFsWalker::detect_moves`, removed entries are handed at the end of the session instead
+    fn process_removed(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _item: Self::Item,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// an entry was moved from `from` to `path` since the last session, see `MemoizedFsWalker::detect_moves`.
+    /// `item` is its cached item, the entry is then processed at its new path only if it changed
+    fn process_moved(
+        &mut self,
+        _from: &Path,
+        _from_mount_path: &Path,
+        _path: &Path,
+        _mount_path: &Path,
+        _item: &Self::Item,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// called by `MemoizedFsWalkerSession::finish_processing` once every path was added, before `fill_report`
+    fn finish_processing(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// complete the report of a session with what only the processor knows, like removed entries,
+    /// called by `MemoizedFsWalkerSession::finish_processing`
+    fn fill_report(&self, _report: &mut SessionReport) {}
+
+    /// name of this kind of processor, saved with the sessions
+    fn kind(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// File system processor able to process files from several threads at once,
+/// used by `MemoizedFsWalkerSession::add_path_parallel`
+#[cfg(feature = "parallel")]
+pub trait ParallelFsProcessor: FsProcessor + Sync {
+    /// process a file from a worker thread, return an item, this item will be cached in the `MemoizedFsWalker` database
+    fn process_file_shared(
+        &self,
+        path: &Path,
+        mount_path: &Path,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item>;
 }
@@ -1,21 +1,99 @@
 use anyhow::Result;
 use std::{
-    cmp::max, collections::HashMap, fs::read_dir, marker::PhantomData, path::Path, time::SystemTime,
+    cmp::max,
+    collections::{HashMap, HashSet},
+    io,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use super::{FsEntry, FsProcessor};
+use super::{
+    change_detector::{ChangeDetector, FsStat},
+    exclude::{ExcludeRules, PathFilter},
+    report::{ChangeCounts, EntryCounts, SessionReport},
+    session::SessionInfo,
+    vfs::{StdFs, Vfs, VfsDirEntry, VfsFileType},
+    FsEntry, FsProcessor, SpecialKind,
+};
+
+#[cfg(feature = "parallel")]
+mod parallel;
+
+/// Outcome of a cache lookup
+pub enum CacheLookup<I> {
+    /// the cached entry is up to date
+    Hit(FsEntry<I>),
+    /// the item has to be computed, holds the previously cached item if any
+    Miss(Option<I>),
+}
 
 pub trait MemoizedFsCacheSession<I> {
     type Cache: MemoizedFsCache<I, Session = Self>;
-    // get the latest id for this path and mtime, optionally computing the new id if necessary
+
+    // look for an entry of this path still up to date according to `detector`, a hit marks the entry as seen by this session
+    // when `force_update` is set the lookup always misses
+    fn lookup_entry(
+        &mut self,
+        path: &Path,
+        stat: &FsStat,
+        detector: ChangeDetector,
+        force_update: bool,
+    ) -> Result<CacheLookup<I>>;
+
+    // check if an entry is cached for this path, without marking it as seen
+    fn has_entry(&mut self, path: &Path) -> Result<bool>;
+
+    // mark the cached entry of this path and every entry below it as seen by this session, without checking them
+    // return the cached entry of this path if any
+    fn keep_entry(&mut self, path: &Path) -> Result<Option<FsEntry<I>>>;
+
+    // save a freshly computed item for this path and mark it as seen by this session
+    fn store_entry(&mut self, path: &Path, stat: &FsStat, item: I) -> Result<FsEntry<I>>;
+
+    // get the latest id for this path and stat, optionally computing the new id if necessary
+    // when `force_update` is set the item is recomputed even if the entry did not change
     fn get_update_entry_from_cache<Op>(
         &mut self,
         path: &Path,
-        mtime: SystemTime,
+        stat: &FsStat,
+        detector: ChangeDetector,
+        force_update: bool,
         compute_item: Op,
     ) -> Result<FsEntry<I>>
     where
-        Op: FnOnce(Option<I>) -> Result<I>;
+        Op: FnOnce(Option<I>) -> Result<I>,
+    {
+        match self.lookup_entry(path, stat, detector, force_update)? {
+            CacheLookup::Hit(entry) => Ok(entry),
+            CacheLookup::Miss(previous) => {
+                let item = compute_item(previous)?;
+                self.store_entry(path, stat, item)
+            }
+        }
+    }
+
+    // paths of the cached entries with the inode and device of `stat`, candidates for the origin of a moved entry
+    fn lookup_inode(&mut self, stat: &FsStat) -> Result<Vec<PathBuf>>;
+
+    // move the cached entry of `from` and every entry below it to `to`, replacing the entries cached below `to`
+    // the moved entries are saved by this session but not marked as seen, return the entry moved to `to` if any
+    fn move_entries(&mut self, from: &Path, to: &Path) -> Result<Option<FsEntry<I>>>;
+
+    // cached entries below `path`, except the ones of the children of `path` named in `kept_children`, in any order
+    // the entries are neither marked as seen nor removed
+    fn list_descendants(
+        &mut self,
+        path: &Path,
+        kept_children: &HashSet<PathBuf>,
+    ) -> Result<Vec<(PathBuf, I)>>;
+
+    // a path given to `add_path`, `end_session` only removes the entries not seen under these roots
+    fn add_root(&mut self, path: &Path) -> Result<()>;
+
+    // save the description of this session, called once right before `end_session`
+    fn record_session(&mut self, info: &SessionInfo) -> Result<()>;
 
     fn end_session(self) -> Result<Self::Cache>;
 
@@ -24,29 +102,166 @@ pub trait MemoizedFsCacheSession<I> {
 
 pub trait MemoizedFsCache<I> {
     type Session: MemoizedFsCacheSession<I, Cache = Self>;
-    fn start_session(self) -> Result<Self::Session>;
+    // start a session working on the entries of `namespace`, the entries of other namespaces are left untouched
+    fn start_session(self, namespace: &str) -> Result<Self::Session>;
+
+    // start a session that only reads the cache, stored entries are returned without being saved,
+    // no entry is removed by `end_session` and the session id is not recorded
+    fn start_dry_run_session(self, namespace: &str) -> Result<Self::Session>;
 }
 
-/// Use a database and file mtime to skip visit of unchanged fs items
+/// Use a database and file metadata to skip visit of unchanged fs items
 pub struct MemoizedFsWalker<I, C: MemoizedFsCache<I>> {
     cache: C,
+    options: WalkOptions,
     _ph: PhantomData<I>,
 }
 
+/// Walk settings, kept by the walker from one session to the next
+#[derive(Clone)]
+struct WalkOptions {
+    propagate_changes: bool,
+    detector: ChangeDetector,
+    racy_window: Option<Duration>,
+    tolerate_errors: bool,
+    exclude: ExcludeRules,
+    dry_run: bool,
+    namespace: String,
+    #[cfg(feature = "parallel")]
+    threads: usize,
+    vfs: Arc<dyn Vfs>,
+    detect_moves: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            propagate_changes: false,
+            detector: ChangeDetector::default(),
+            racy_window: None,
+            tolerate_errors: false,
+            exclude: ExcludeRules::default(),
+            dry_run: false,
+            namespace: String::new(),
+            #[cfg(feature = "parallel")]
+            threads: 0,
+            vfs: Arc::new(StdFs),
+            detect_moves: false,
+        }
+    }
+}
+
 impl<I, C: MemoizedFsCache<I>> MemoizedFsWalker<I, C> {
     pub fn new(cache: C) -> Self {
         Self {
             cache,
+            options: WalkOptions::default(),
             _ph: PhantomData,
         }
     }
+
+    /// When enabled, a folder is processed again as soon as one of its descendants was processed,
+    /// so folder items computed from their children (tree hash, size...) stay up to date.
+    /// The mtime of a folder entry is then the most recent mtime of its subtree.
+    pub fn propagate_changes(mut self, enabled: bool) -> Self {
+        self.options.propagate_changes = enabled;
+        self
+    }
+
+    /// Policy deciding if a cached entry is still up to date, `ChangeDetector::Mtime` by default
+    pub fn change_detector(mut self, detector: ChangeDetector) -> Self {
+        self.options.detector = detector;
+        self
+    }
+
+    /// Entries modified less than `window` before the start of a session can be modified again
+    /// without changing their metadata, they are processed again by the next session instead of being trusted.
+    /// Use at least the timestamp granularity of the file system, disabled by default
+    pub fn racy_window(mut self, window: Option<Duration>) -> Self {
+        self.options.racy_window = window;
+        self
+    }
+
+    /// When enabled, an entry that can not be read or processed is reported in the `WalkError` list
+    /// returned by `finish_processing` instead of aborting the session, its cached item and the
+    /// cached items below it are kept as is. Errors on the paths given to `add_path` are still returned
+    pub fn tolerate_errors(mut self, enabled: bool) -> Self {
+        self.options.tolerate_errors = enabled;
+        self
+    }
+
+    /// Skip the entries matching these rules, a previously cached entry that becomes excluded
+    /// is processed like a removed one
+    pub fn exclude(mut self, rules: ExcludeRules) -> Self {
+        self.options.exclude = rules;
+        self
+    }
+
+    /// When enabled, sessions compute and report the changes without updating the cache,
+    /// the next session sees the same changes again
+    pub fn dry_run(mut self, enabled: bool) -> Self {
+        self.options.dry_run = enabled;
+        self
+    }
+
+    /// Cache namespace of the sessions, each namespace keeps its own entries and sessions
+    /// so several incremental streams can share one cache. The default namespace is ""
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.options.namespace = namespace.into();
+        self
+    }
+
+    /// Number of threads used by `MemoizedFsWalkerSession::add_path_parallel`, 0 means one per cpu
+    #[cfg(feature = "parallel")]
+    pub fn parallelism(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// File system walked by the sessions, `StdFs` by default.
+    /// Processors reading the entries, like `TarProcessor`, have to be given the same one
+    pub fn vfs(mut self, vfs: Arc<dyn Vfs>) -> Self {
+        self.options.vfs = vfs;
+        self
+    }
+
+    /// When enabled, a new entry with the inode and device of a cached entry whose path disappeared
+    /// is handled as moved: the cached subtree moves along and the processor is told with
+    /// `FsProcessor::process_moved` instead of processing the subtree again, so items must not depend on their path.
+    /// Only the entries under the paths already added to the session are matched, and removed entries are
+    /// handed to `FsProcessor::process_removed` at the end of the session. Ignored by dry runs
+    pub fn detect_moves(mut self, enabled: bool) -> Self {
+        self.options.detect_moves = enabled;
+        self
+    }
+
     pub fn start_processing<F: FsProcessor<Item = I>>(
         self,
         fs_processor: F,
     ) -> Result<MemoizedFsWalkerSession<F, C::Session>> {
+        let started_at = self.options.vfs.now();
+        let racy_threshold = self
+            .options
+            .racy_window
+            .map(|window| started_at.checked_sub(window).unwrap_or(started_at));
+        let session = if self.options.dry_run {
+            self.cache.start_dry_run_session(&self.options.namespace)?
+        } else {
+            self.cache.start_session(&self.options.namespace)?
+        };
+        let info = SessionInfo::new(&self.options.namespace, started_at, fs_processor.kind());
         Ok(MemoizedFsWalkerSession {
             fs_processor,
-            session: self.cache.start_session()?,
+            session,
+            options: self.options,
+            info,
+            racy_threshold,
+            timer: Instant::now(),
+            report: SessionReport::default(),
+            removed: Vec::new(),
+            moved_from: Vec::new(),
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
         })
     }
 }
@@ -55,55 +270,510 @@ impl<I, C: MemoizedFsCache<I>> MemoizedFsWalker<I, C> {
 pub struct MemoizedFsWalkerSession<F: FsProcessor, S: MemoizedFsCacheSession<F::Item>> {
     fs_processor: F,
     session: S,
+    options: WalkOptions,
+    info: SessionInfo,
+    /// entries modified after this are racy
+    racy_threshold: Option<SystemTime>,
+    timer: Instant,
+    report: SessionReport,
+    /// path, mount path and item of the removed entries, handed at the end of the session when moves are detected
+    removed: Vec<(PathBuf, PathBuf, F::Item)>,
+    /// paths the moved entries come from
+    moved_from: Vec<PathBuf>,
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<rayon::ThreadPool>,
+}
+
+/// Entry skipped because of an error, see `MemoizedFsWalker::tolerate_errors`
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub mount_path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// folder being walked by `MemoizedFsWalkerSession::visit_path`
+struct FolderFrame<I> {
+    path: PathBuf,
+    mount_path: PathBuf,
+    stat: FsStat,
+    filter: PathFilter,
+    subs: std::vec::IntoIter<VfsDirEntry>,
+    /// name, path and mount path of the child being visited
+    current: Option<(PathBuf, PathBuf, PathBuf)>,
+    children: Vec<(PathBuf, FsEntry<I>, bool)>,
+    excluded: Vec<PathBuf>,
 }
 
+impl<I> FolderFrame<I> {
+    /// next child to visit, excluded children are skipped
+    fn next_child(&mut self) -> Result<Option<(PathBuf, PathBuf)>> {
+        for entry in &mut self.subs {
+            let entry_path = self.path.join(&entry.name);
+            if self
+                .filter
+                .is_excluded(&entry_path, entry.file_type.is_dir())
+            {
+                self.excluded.push(entry_path);
+                continue;
+            }
+            return Ok(Some((entry.name.into(), entry_path)));
+        }
+        Ok(None)
+    }
+}
+
+enum Visited<I> {
+    Entry(FsEntry<I>, bool),
+    Folder(Box<FolderFrame<I>>),
+}
+
+type FinishedSession<F, S> = (
+    MemoizedFsWalker<
+        <F as FsProcessor>::Item,
+        <S as MemoizedFsCacheSession<<F as FsProcessor>::Item>>::Cache,
+    >,
+    SessionReport,
+);
+
 impl<F: FsProcessor, S: MemoizedFsCacheSession<F::Item>> MemoizedFsWalkerSession<F, S> {
     pub fn add_path(
         &mut self,
         path: impl AsRef<Path>,
         mount_path: impl AsRef<Path>,
     ) -> Result<FsEntry<F::Item>> {
-        let path = path.as_ref();
-        let mount_path = mount_path.as_ref();
-        let meta = path.symlink_metadata()?;
-        let mtime = meta.modified()?;
-        let ft = meta.file_type();
-        if ft.is_file() {
-            let fs_hasher = &mut self.fs_processor;
-            self.session
-                .get_update_entry_from_cache(path, mtime, |opt_prev| {
-                    fs_hasher.process_file(path, mount_path, opt_prev)
-                })
-        } else if ft.is_dir() {
-            let mut max_mtime = mtime;
-            let mut entry_map = HashMap::new();
-            for sub in read_dir(path)? {
-                let entry = sub?;
-                let new_mount_path = mount_path.join(entry.file_name());
-                let folder_entry = self.add_path(&entry.path(), new_mount_path)?;
-                max_mtime = max(max_mtime, folder_entry.mtime);
-                entry_map.insert(entry.file_name().into(), folder_entry);
+        let (path, mount_path) = (path.as_ref(), mount_path.as_ref());
+        self.add_root(path, mount_path)?;
+        let filter = PathFilter::new(&self.options.exclude, path)?;
+        let (entry, _changed) = self.visit_path(path, mount_path, &filter)?;
+        Ok(entry)
+    }
+
+    fn add_root(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        self.info
+            .roots
+            .push((path.to_path_buf(), mount_path.to_path_buf()));
+        self.session.add_root(path)
+    }
+
+    /// visit a path and return its entry along with whether it was (re)processed during this visit
+    /// folders are walked with an explicit stack, each folder being processed after all its children
+    fn visit_path(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        filter: &PathFilter,
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        let mut stack: Vec<FolderFrame<F::Item>> = Vec::new();
+        let mut visited = self.enter_path(path, mount_path, filter);
+        loop {
+            let mut completed = match visited {
+                Ok(Visited::Folder(frame)) => {
+                    stack.push(*frame);
+                    None
+                }
+                Ok(Visited::Entry(entry, changed)) => Some(Ok((entry, changed))),
+                Err(error) => Some(Err(error)),
+            };
+            // hand completed entries to their parent until a folder has a child left to visit
+            visited = loop {
+                if let Some(result) = completed.take() {
+                    match stack.last_mut() {
+                        Some(parent) => self.add_child(parent, result)?,
+                        None => return result,
+                    }
+                }
+
+                let frame = stack.last_mut().expect("a folder is being visited");
+                match frame.next_child() {
+                    Ok(Some((name, child_path))) => {
+                        let child_mount_path = frame.mount_path.join(&name);
+                        let visited =
+                            self.enter_path(&child_path, &child_mount_path, &frame.filter);
+                        frame.current = Some((name, child_path, child_mount_path));
+                        break visited;
+                    }
+                    Ok(None) => {
+                        let frame = stack.pop().unwrap();
+                        completed = Some(self.update_folder(
+                            &frame.path,
+                            &frame.mount_path,
+                            &frame.stat,
+                            frame.children,
+                            &frame.excluded,
+                        ));
+                    }
+                    Err(error) => {
+                        stack.pop();
+                        completed = Some(Err(error));
+                    }
+                }
+            };
+        }
+    }
+
+    /// hand the visited child of `parent` to it, a failed child is skipped when errors are tolerated
+    fn add_child(
+        &mut self,
+        parent: &mut FolderFrame<F::Item>,
+        result: Result<(FsEntry<F::Item>, bool)>,
+    ) -> Result<()> {
+        let (name, child_path, child_mount_path) =
+            parent.current.take().expect("a child is being visited");
+        match result {
+            Ok((entry, changed)) => parent.children.push((name, entry, changed)),
+            Err(error) if self.options.tolerate_errors => {
+                let kept = self.skip_failed(&child_path, &child_mount_path, error)?;
+                if let Some(entry) = kept {
+                    parent.children.push((name, entry, false));
+                }
             }
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
 
-            let fs_hasher = &mut self.fs_processor;
-            self.session
-                .get_update_entry_from_cache(path, mtime, |opt_prev| {
-                    fs_hasher.process_folder(path, mount_path, entry_map, opt_prev)
-                })
-        } else {
-            let fs_hasher = &mut self.fs_processor;
-            self.session
-                .get_update_entry_from_cache(path, mtime, |opt_prev| {
-                    fs_hasher.process_symlink(path, mount_path, opt_prev)
-                })
+    /// stat a path, process it unless it is a folder which is returned to be walked
+    fn enter_path(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        filter: &PathFilter,
+    ) -> Result<Visited<F::Item>> {
+        let vfs = self.options.vfs.clone();
+        let meta = vfs.symlink_metadata(path)?;
+        let mut stat = self.options.detector.stat(&*vfs, path, &meta)?;
+        if let Some(threshold) = self.racy_threshold {
+            stat.check_racy(threshold);
+        }
+        self.detect_move(path, mount_path, &stat)?;
+        let (entry, changed) = match meta.file_type {
+            VfsFileType::File => self.update_file(path, mount_path, &stat)?,
+            VfsFileType::Folder => {
+                let mut subs = vfs.read_dir(path)?;
+                // visit children in a stable order, whatever the order returned by the file system
+                subs.sort_by(|a, b| a.name.cmp(&b.name));
+                return Ok(Visited::Folder(Box::new(FolderFrame {
+                    path: path.to_path_buf(),
+                    mount_path: mount_path.to_path_buf(),
+                    stat,
+                    filter: filter.enter_folder(&*vfs, path)?,
+                    subs: subs.into_iter(),
+                    current: None,
+                    children: Vec::new(),
+                    excluded: Vec::new(),
+                })));
+            }
+            VfsFileType::Symlink => self.update_symlink(path, mount_path, &stat)?,
+            VfsFileType::Special(kind) => self.update_special(path, mount_path, &stat, kind)?,
+        };
+        Ok(Visited::Entry(entry, changed))
+    }
+
+    /// when `path` is not cached, look for the cached entry it was moved from and move its cached subtree to `path`
+    fn detect_move(&mut self, path: &Path, mount_path: &Path, stat: &FsStat) -> Result<()> {
+        // inodes are 0 on file systems without them
+        if !self.detects_moves() || stat.inode == 0 || self.session.has_entry(path)? {
+            return Ok(());
+        }
+        for from in self.session.lookup_inode(stat)? {
+            if from.starts_with(path) || path.starts_with(&from) {
+                continue;
+            }
+            let from_mount_path = match self.root_mount_path(&from) {
+                Some(from_mount_path) => from_mount_path,
+                None => continue,
+            };
+            // the cached path can still be there, as another hard link of the entry
+            match self.options.vfs.symlink_metadata(&from) {
+                Ok(meta) if meta.inode == stat.inode && meta.device == stat.device => continue,
+                Ok(_) => {}
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(_) => continue,
+            }
+            if let Some(entry) = self.session.move_entries(&from, path)? {
+                self.fs_processor.process_moved(
+                    &from,
+                    &from_mount_path,
+                    path,
+                    mount_path,
+                    &entry.item,
+                )?;
+                self.moved_from.push(from);
+            }
+            break;
+        }
+        Ok(())
+    }
+
+    /// moves are not detected by dry runs, as they would have to move the cached entries
+    fn detects_moves(&self) -> bool {
+        self.options.detect_moves && !self.options.dry_run
+    }
+
+    /// hand the entries cached below `path` that are gone to the processor, each folder after its descendants.
+    /// `kept_children` are the children of `path` still there, empty when `path` is no longer a folder
+    fn remove_descendants(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kept_children: &HashSet<PathBuf>,
+    ) -> Result<()> {
+        let removed = self.session.list_descendants(path, kept_children)?;
+        for (removed_path, item) in descendants_first(removed) {
+            let removed_mount_path = mount_path.join(removed_path.strip_prefix(path)?);
+            if self.detects_moves() {
+                // the entry may show up at another path later in the session
+                self.removed.push((removed_path, removed_mount_path, item));
+            } else {
+                self.fs_processor
+                    .process_removed(&removed_path, &removed_mount_path, item)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// mount path of `path` according to the roots added so far
+    fn root_mount_path(&self, path: &Path) -> Option<PathBuf> {
+        self.info.roots.iter().find_map(|(root, root_mount_path)| {
+            let relative = path.strip_prefix(root).ok()?;
+            Some(if relative.as_os_str().is_empty() {
+                root_mount_path.clone()
+            } else {
+                root_mount_path.join(relative)
+            })
+        })
+    }
+
+    /// record the error of an entry and keep its cached subtree, return its cached entry if any
+    fn skip_failed(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        error: anyhow::Error,
+    ) -> Result<Option<FsEntry<F::Item>>> {
+        self.report.errors.push(WalkError {
+            path: path.to_path_buf(),
+            mount_path: mount_path.to_path_buf(),
+            error,
+        });
+        self.session.keep_entry(path)
+    }
+
+    /// process a folder once all its children have been visited,
+    /// `excluded` children still in the cache are removed by processing the folder again
+    fn update_folder(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: &FsStat,
+        children: Vec<(PathBuf, FsEntry<F::Item>, bool)>,
+        excluded: &[PathBuf],
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        let mut max_mtime = stat.mtime;
+        let mut sub_changed = false;
+        let mut entry_map = HashMap::with_capacity(children.len());
+        let mut kept_children = HashSet::with_capacity(children.len());
+        for (name, child, child_changed) in children {
+            max_mtime = max(max_mtime, child.mtime);
+            sub_changed |= child_changed;
+            kept_children.insert(name.clone());
+            entry_map.insert(name, child);
+        }
+
+        let mut force_update = self.options.propagate_changes && sub_changed;
+        for excluded_path in excluded {
+            if force_update {
+                break;
+            }
+            force_update = self.session.has_entry(excluded_path)?;
         }
+
+        let (mut entry, changed) = self.update_entry(
+            path,
+            mount_path,
+            stat,
+            force_update,
+            &kept_children,
+            |changes| &mut changes.folders,
+            |fs_processor, opt_prev| {
+                fs_processor.process_folder(path, mount_path, entry_map, opt_prev)
+            },
+        )?;
+        if self.options.propagate_changes {
+            entry.mtime = max_mtime;
+        }
+        Ok((entry, changed))
+    }
+
+    /// get the cached entry or process it again, counting the outcome in the report.
+    /// When processed again, the entries cached below `path` apart from `kept_children` are removed once it is stored
+    #[allow(clippy::too_many_arguments)]
+    fn update_entry<P>(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: &FsStat,
+        force_update: bool,
+        kept_children: &HashSet<PathBuf>,
+        counts: fn(&mut ChangeCounts) -> &mut EntryCounts,
+        process: P,
+    ) -> Result<(FsEntry<F::Item>, bool)>
+    where
+        P: FnOnce(&mut F, Option<F::Item>) -> Result<F::Item>,
+    {
+        let previous =
+            match self
+                .session
+                .lookup_entry(path, stat, self.options.detector, force_update)?
+            {
+                CacheLookup::Hit(entry) => {
+                    self.report.count(counts, None);
+                    return Ok((entry, false));
+                }
+                CacheLookup::Miss(previous) => previous,
+            };
+        let had_previous = previous.is_some();
+        let item = process(&mut self.fs_processor, previous)?;
+        let entry = self.session.store_entry(path, stat, item)?;
+        if had_previous {
+            // not before, an entry failing to be processed keeps its cached subtree until the next session
+            self.remove_descendants(path, mount_path, kept_children)?;
+        }
+        self.report.count(counts, Some(had_previous));
+        Ok((entry, true))
     }
 
-    pub fn finish_processing(self) -> Result<(MemoizedFsWalker<F::Item, S::Cache>, u32)> {
-        let session_id = self.session.get_id();
+    fn update_file(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: &FsStat,
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        let (entry, changed) = self.update_entry(
+            path,
+            mount_path,
+            stat,
+            false,
+            &HashSet::new(),
+            |changes| &mut changes.files,
+            |fs_processor, opt_prev| fs_processor.process_file(path, mount_path, opt_prev),
+        )?;
+        self.report.count_hashed(stat);
+        Ok((entry, changed))
+    }
+
+    fn update_symlink(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: &FsStat,
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        self.update_entry(
+            path,
+            mount_path,
+            stat,
+            false,
+            &HashSet::new(),
+            |changes| &mut changes.symlinks,
+            |fs_processor, opt_prev| fs_processor.process_symlink(path, mount_path, opt_prev),
+        )
+    }
+
+    fn update_special(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: &FsStat,
+        kind: SpecialKind,
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        self.update_entry(
+            path,
+            mount_path,
+            stat,
+            false,
+            &HashSet::new(),
+            |changes| &mut changes.specials,
+            |fs_processor, opt_prev| fs_processor.process_special(path, mount_path, kind, opt_prev),
+        )
+    }
+
+    /// time at which this session started
+    pub fn started_at(&self) -> SystemTime {
+        self.info.started_at
+    }
+
+    /// Free text saved with the session, see `list_sessions`
+    pub fn set_label(&mut self, label: impl Into<String>) {
+        self.info.label = Some(label.into());
+    }
+
+    /// What this session produced, like the path or the checksum of a tar file, see `list_sessions`
+    pub fn set_artifact(&mut self, artifact: impl Into<String>) {
+        self.info.artifact = Some(artifact.into());
+    }
+
+    /// report of the session so far
+    pub fn report(&self) -> &SessionReport {
+        &self.report
+    }
+
+    pub fn finish_processing(mut self) -> Result<FinishedSession<F, S>> {
+        let mut report = self.report;
+        report.session_id = self.session.get_id();
+        report.elapsed = self.timer.elapsed();
+        for (path, mount_path, item) in std::mem::take(&mut self.removed) {
+            if !self.moved_from.iter().any(|from| path.starts_with(from)) {
+                self.fs_processor
+                    .process_removed(&path, &mount_path, item)?;
+            }
+        }
+        self.fs_processor.finish_processing()?;
+        self.fs_processor.fill_report(&mut report);
+        let mut info = self.info;
+        info.session_id = report.session_id;
+        info.ended_at = Some(self.options.vfs.now());
+        self.session.record_session(&info)?;
         Ok((
-            MemoizedFsWalker::new(self.session.end_session()?),
-            session_id,
+            MemoizedFsWalker {
+                cache: self.session.end_session()?,
+                options: self.options,
+                _ph: PhantomData,
+            },
+            report,
         ))
     }
 }
+
+/// true if `descendant`, a path below `path`, is not below one of the children of `path` named in `kept_children`
+pub(crate) fn is_removed_descendant(
+    path: &Path,
+    descendant: &Path,
+    kept_children: &HashSet<PathBuf>,
+) -> bool {
+    let child = descendant
+        .strip_prefix(path)
+        .ok()
+        .and_then(|relative| relative.components().next());
+    child.is_some_and(|child| !kept_children.contains(Path::new(child.as_os_str())))
+}
+
+/// order entries so each folder comes after its descendants, siblings being sorted by name
+fn descendants_first<T>(mut entries: Vec<(PathBuf, T)>) -> Vec<(PathBuf, T)> {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut ordered = Vec::with_capacity(entries.len());
+    // ancestors of the current entry, waiting for their descendants
+    let mut ancestors: Vec<(PathBuf, T)> = Vec::new();
+    for entry in entries {
+        while let Some(ancestor) = ancestors.pop() {
+            if entry.0.starts_with(&ancestor.0) {
+                ancestors.push(ancestor);
+                break;
+            }
+            ordered.push(ancestor);
+        }
+        ancestors.push(entry);
+    }
+    ordered.extend(ancestors.into_iter().rev());
+    ordered
+}
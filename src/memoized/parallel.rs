@@ -0,0 +1,327 @@
+use anyhow::Result;
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::{CacheLookup, FolderFrame, MemoizedFsCacheSession, MemoizedFsWalkerSession};
+use crate::{
+    change_detector::{ChangeDetector, FsStat},
+    exclude::PathFilter,
+    vfs::{Vfs, VfsDirEntry, VfsFileType},
+    FsEntry, ParallelFsProcessor,
+};
+
+/// settings of the stat calls, shared by all the threads
+#[derive(Clone, Copy)]
+struct StatOptions<'a> {
+    vfs: &'a dyn Vfs,
+    detector: ChangeDetector,
+    racy_threshold: Option<SystemTime>,
+}
+
+impl StatOptions<'_> {
+    fn stat(&self, path: &Path) -> Result<(FsStat, VfsFileType)> {
+        let meta = self.vfs.symlink_metadata(path)?;
+        let mut stat = self.detector.stat(self.vfs, path, &meta)?;
+        if let Some(threshold) = self.racy_threshold {
+            stat.check_racy(threshold);
+        }
+        Ok((stat, meta.file_type))
+    }
+
+    fn read_folder(&self, path: &Path, filter: &PathFilter) -> Result<FolderListing> {
+        let mut subs = self.vfs.read_dir(path)?;
+        // visit children in a stable order, whatever the order returned by the file system
+        subs.sort_by(|a, b| a.name.cmp(&b.name));
+        let filter = filter.enter_folder(self.vfs, path)?;
+        Ok(FolderListing { subs, filter })
+    }
+}
+
+/// children of a folder, along with the filter applied to them
+struct FolderListing {
+    subs: Vec<VfsDirEntry>,
+    filter: PathFilter,
+}
+
+/// child of a folder, stat along with its siblings. Children that failed hold their error,
+/// child folders also hold their listing, read by the thread pool too
+struct StatChild {
+    name: PathBuf,
+    path: PathBuf,
+    stat: Result<(FsStat, VfsFileType)>,
+    listing: Option<Result<FolderListing>>,
+}
+
+/// folder being walked by `MemoizedFsWalkerSession::visit_path_parallel`,
+/// its files are already processed when it is entered, `pending` are its other children left to visit
+struct ParallelFrame<I> {
+    frame: FolderFrame<I>,
+    pending: std::vec::IntoIter<StatChild>,
+}
+
+enum ParallelVisited<I> {
+    Entry(FsEntry<I>, bool),
+    Folder(Box<ParallelFrame<I>>),
+}
+
+impl<F, S> MemoizedFsWalkerSession<F, S>
+where
+    F: ParallelFsProcessor,
+    F::Item: Send,
+    S: MemoizedFsCacheSession<F::Item>,
+{
+    /// Same as `add_path`, but the children of each folder are stat, its sub folders are read and its files
+    /// are processed by a thread pool. Cache accesses, symlinks and folders are still handled by the calling thread
+    pub fn add_path_parallel(
+        &mut self,
+        path: impl AsRef<Path>,
+        mount_path: impl AsRef<Path>,
+    ) -> Result<FsEntry<F::Item>> {
+        let (path, mount_path) = (path.as_ref(), mount_path.as_ref());
+        self.add_root(path, mount_path)?;
+        if self.thread_pool.is_none() {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(self.options.threads)
+                .build()?;
+            self.thread_pool = Some(pool);
+        }
+        let filter = PathFilter::new(&self.options.exclude, path)?;
+        let vfs = self.options.vfs.clone();
+        let options = StatOptions {
+            vfs: &*vfs,
+            detector: self.options.detector,
+            racy_threshold: self.racy_threshold,
+        };
+        let (entry, _changed) = self.visit_path_parallel(path, mount_path, &filter, options)?;
+        Ok(entry)
+    }
+
+    /// same walk as `visit_path`, with an explicit stack of folders
+    fn visit_path_parallel(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        filter: &PathFilter,
+        options: StatOptions,
+    ) -> Result<(FsEntry<F::Item>, bool)> {
+        let mut stack: Vec<ParallelFrame<F::Item>> = Vec::new();
+        let mut visited = options.stat(path).and_then(|(stat, file_type)| {
+            self.detect_move(path, mount_path, &stat)?;
+            self.enter_path_parallel(path, mount_path, stat, file_type, None, filter, options)
+        });
+        loop {
+            let mut completed = match visited {
+                Ok(ParallelVisited::Folder(frame)) => {
+                    stack.push(*frame);
+                    None
+                }
+                Ok(ParallelVisited::Entry(entry, changed)) => Some(Ok((entry, changed))),
+                Err(error) => Some(Err(error)),
+            };
+            // hand completed entries to their parent until a folder has a child left to visit
+            visited = loop {
+                if let Some(result) = completed.take() {
+                    match stack.last_mut() {
+                        Some(parent) => self.add_child(&mut parent.frame, result)?,
+                        None => return result,
+                    }
+                }
+
+                let top = stack.last_mut().expect("a folder is being visited");
+                match top.pending.next() {
+                    Some(StatChild {
+                        name,
+                        path,
+                        stat,
+                        listing,
+                    }) => {
+                        let child_mount_path = top.frame.mount_path.join(&name);
+                        let visited = stat.and_then(|(stat, file_type)| {
+                            self.enter_path_parallel(
+                                &path,
+                                &child_mount_path,
+                                stat,
+                                file_type,
+                                listing,
+                                &top.frame.filter,
+                                options,
+                            )
+                        });
+                        top.frame.current = Some((name, path, child_mount_path));
+                        break visited;
+                    }
+                    None => {
+                        let frame = stack.pop().unwrap().frame;
+                        completed = Some(self.update_folder(
+                            &frame.path,
+                            &frame.mount_path,
+                            &frame.stat,
+                            frame.children,
+                            &frame.excluded,
+                        ));
+                    }
+                }
+            };
+        }
+    }
+
+    /// process a stat path unless it is a folder, which is returned to be walked once
+    /// its children are stat and its files are processed by the thread pool.
+    /// A folder without a `listing` read by the thread pool, like the root, is read by the calling thread
+    #[allow(clippy::too_many_arguments)]
+    fn enter_path_parallel(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        stat: FsStat,
+        file_type: VfsFileType,
+        listing: Option<Result<FolderListing>>,
+        filter: &PathFilter,
+        options: StatOptions,
+    ) -> Result<ParallelVisited<F::Item>> {
+        let (entry, changed) = match file_type {
+            VfsFileType::File => self.update_file(path, mount_path, &stat)?,
+            VfsFileType::Folder => {
+                let FolderListing { subs, filter } = match listing {
+                    Some(listing) => listing?,
+                    None => options.read_folder(path, filter)?,
+                };
+                let mut frame = FolderFrame {
+                    path: path.to_path_buf(),
+                    mount_path: mount_path.to_path_buf(),
+                    stat,
+                    filter,
+                    subs: subs.into_iter(),
+                    current: None,
+                    children: Vec::new(),
+                    excluded: Vec::new(),
+                };
+                let mut included = Vec::new();
+                while let Some(child) = frame.next_child()? {
+                    included.push(child);
+                }
+                let pool = self.thread_pool.as_ref().unwrap();
+                let filter = &frame.filter;
+                let children = pool.install(|| {
+                    included
+                        .into_par_iter()
+                        .map(|(name, path)| {
+                            let stat = options.stat(&path);
+                            let listing = match &stat {
+                                Ok((_, VfsFileType::Folder)) => {
+                                    Some(options.read_folder(&path, filter))
+                                }
+                                _ => None,
+                            };
+                            StatChild {
+                                name,
+                                path,
+                                stat,
+                                listing,
+                            }
+                        })
+                        .collect()
+                });
+                let pending = self.process_files(&mut frame, children, options)?;
+                return Ok(ParallelVisited::Folder(Box::new(ParallelFrame {
+                    frame,
+                    pending: pending.into_iter(),
+                })));
+            }
+            VfsFileType::Symlink => self.update_symlink(path, mount_path, &stat)?,
+            VfsFileType::Special(kind) => self.update_special(path, mount_path, &stat, kind)?,
+        };
+        Ok(ParallelVisited::Entry(entry, changed))
+    }
+
+    /// detect the moves of the children of `frame`, look its files up in the cache and process the missing ones
+    /// with the thread pool. Return the other children, and the failed ones when errors are tolerated
+    fn process_files(
+        &mut self,
+        frame: &mut FolderFrame<F::Item>,
+        children: Vec<StatChild>,
+        options: StatOptions,
+    ) -> Result<Vec<StatChild>> {
+        let mut pending = Vec::new();
+        let mut todo = Vec::new();
+        for child in children {
+            let (stat, file_type) = match &child.stat {
+                Ok((stat, file_type)) => (stat, *file_type),
+                Err(_) => {
+                    pending.push(child);
+                    continue;
+                }
+            };
+            let mount_path = frame.mount_path.join(&child.name);
+            let lookup = self
+                .detect_move(&child.path, &mount_path, stat)
+                .and_then(|()| match file_type {
+                    VfsFileType::File => self
+                        .session
+                        .lookup_entry(&child.path, stat, options.detector, false)
+                        .map(Some),
+                    _ => Ok(None),
+                });
+            match lookup {
+                Ok(None) => pending.push(child),
+                Ok(Some(CacheLookup::Hit(entry))) => {
+                    self.report.count(|changes| &mut changes.files, None);
+                    self.report.count_hashed(stat);
+                    frame.children.push((child.name, entry, false));
+                }
+                Ok(Some(CacheLookup::Miss(previous))) => {
+                    let had_previous = previous.is_some();
+                    todo.push((child, mount_path, previous, had_previous));
+                }
+                Err(error) => pending.push(self.child_failed(child, error)?),
+            }
+        }
+
+        let pool = self.thread_pool.as_ref().unwrap();
+        let fs_processor = &self.fs_processor;
+        let items: Vec<_> = pool.install(|| {
+            todo.into_par_iter()
+                .map(|(child, mount_path, previous, had_previous)| {
+                    let item = fs_processor.process_file_shared(&child.path, &mount_path, previous);
+                    (child, mount_path, item, had_previous)
+                })
+                .collect()
+        });
+        for (child, mount_path, item, had_previous) in items {
+            let stat = match &child.stat {
+                Ok((stat, _)) => stat,
+                Err(_) => unreachable!("only stat files are processed"),
+            };
+            match item {
+                Ok(item) => {
+                    let entry = self.session.store_entry(&child.path, stat, item)?;
+                    if had_previous {
+                        // a folder replaced by the file
+                        self.remove_descendants(&child.path, &mount_path, &HashSet::new())?;
+                    }
+                    self.report
+                        .count(|changes| &mut changes.files, Some(had_previous));
+                    self.report.count_hashed(stat);
+                    frame.children.push((child.name, entry, true));
+                }
+                Err(error) => pending.push(self.child_failed(child, error)?),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// keep a failed child to be skipped when it is visited, fail the walk unless errors are tolerated
+    fn child_failed(&self, child: StatChild, error: anyhow::Error) -> Result<StatChild> {
+        if !self.options.tolerate_errors {
+            return Err(error);
+        }
+        Ok(StatChild {
+            stat: Err(error),
+            ..child
+        })
+    }
+}
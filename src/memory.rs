@@ -0,0 +1,462 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Bound,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+
+use crate::{
+    memoized::is_removed_descendant, CacheLookup, ChangeDetector, FsEntry, FsStat, MemoizedFsCache,
+    MemoizedFsCacheSession, SessionInfo,
+};
+
+/// Cache kept in memory, with the same semantics as the sqlite one: sessions, namespaces,
+/// pruning of the entries not seen under the roots of a session and rollback
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryCache<I> {
+    namespaces: HashMap<String, MemoryNamespace<I>>,
+    /// from the oldest to the latest
+    sessions: Vec<SessionInfo>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct MemoryNamespace<I> {
+    // ordered by components, the entries below a path directly follow it
+    #[cfg_attr(feature = "serde", serde(with = "crate::path_bytes::children_map"))]
+    entries: BTreeMap<PathBuf, MemoryEntry<I>>,
+    /// previous states of the entries, replaced or removed by a later session
+    history: Vec<MemoryHistoryEntry<I>>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct MemoryEntry<I> {
+    item: I,
+    stat: FsStat,
+    session_id: u32,
+    last_seen_session: u32,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct MemoryHistoryEntry<I> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::path_bytes::path"))]
+    path: PathBuf,
+    entry: MemoryEntry<I>,
+    replaced_session_id: u32,
+}
+
+impl<I> Default for MemoryCache<I> {
+    fn default() -> Self {
+        Self {
+            namespaces: HashMap::new(),
+            sessions: Vec::new(),
+        }
+    }
+}
+
+impl<I> Default for MemoryNamespace<I> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            history: Vec::new(),
+        }
+    }
+}
+
+impl<I> MemoryNamespace<I> {
+    /// `path` and the paths below it
+    fn subtree_paths(&self, path: &Path) -> Vec<PathBuf> {
+        self.entries
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(entry_path, _)| entry_path)
+            .take_while(|entry_path| entry_path.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    /// remove the entry of `path`, keeping it for rollback if it was saved before the session `session_id`
+    fn remove_entry(&mut self, path: &Path, session_id: u32) -> Option<MemoryEntry<I>>
+    where
+        I: Clone,
+    {
+        let entry = self.entries.remove(path)?;
+        if entry.session_id < session_id {
+            self.history.push(MemoryHistoryEntry {
+                path: path.to_path_buf(),
+                entry: entry.clone(),
+                replaced_session_id: session_id,
+            });
+        }
+        Some(entry)
+    }
+
+    /// `path` and the paths below it
+    fn subtree_mut<'a>(
+        &'a mut self,
+        path: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a mut MemoryEntry<I>)> + 'a {
+        self.entries
+            .range_mut::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(move |(entry_path, _)| entry_path.starts_with(path))
+    }
+}
+
+impl<I> MemoryCache<I> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sessions saved in the cache, from the oldest to the latest
+    pub fn sessions(&self) -> &[SessionInfo] {
+        &self.sessions
+    }
+
+    /// Number of entries cached for `namespace`
+    pub fn len(&self, namespace: &str) -> usize {
+        self.namespaces
+            .get(namespace)
+            .map_or(0, |namespace| namespace.entries.len())
+    }
+
+    pub fn is_empty(&self, namespace: &str) -> bool {
+        self.len(namespace) == 0
+    }
+
+    /// Restore the cache of `namespace` as it was at the end of its last session before `id`,
+    /// forgetting the sessions of this namespace from `id`
+    pub fn rollback_before_session_id(&mut self, namespace: &str, id: u32) {
+        if let Some(entries) = self.namespaces.get_mut(namespace) {
+            entries.entries.retain(|_, entry| entry.session_id < id);
+            // entries written before `id` and replaced or removed since
+            for history in std::mem::take(&mut entries.history) {
+                if history.replaced_session_id < id {
+                    entries.history.push(history);
+                } else if history.entry.session_id < id {
+                    entries.entries.insert(history.path, history.entry);
+                }
+            }
+            // every remaining entry was part of the cache at the end of the session `id - 1`
+            for entry in entries.entries.values_mut() {
+                entry.last_seen_session = entry.last_seen_session.min(id.saturating_sub(1));
+            }
+        }
+        self.sessions
+            .retain(|session| session.namespace != namespace || session.session_id < id);
+    }
+
+    /// Forget the history needed to rollback before the session `id`, `rollback_before_session_id`
+    /// can then only restore the session `id - 1` or a later one
+    pub fn prune_history_before_session_id(&mut self, id: u32) {
+        for entries in self.namespaces.values_mut() {
+            entries
+                .history
+                .retain(|history| history.replaced_session_id >= id);
+        }
+    }
+
+    fn next_session_id(&self) -> u32 {
+        self.sessions
+            .iter()
+            .map(|session| session.session_id)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl<I: serde::Serialize + serde::de::DeserializeOwned> MemoryCache<I> {
+    /// Save the whole cache to a file, to be restored with `load_snapshot`
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        bincode::serialize_into(file, self)?;
+        Ok(())
+    }
+
+    pub fn load_snapshot(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(bincode::deserialize_from(file)?)
+    }
+}
+
+pub struct MemoryCacheSession<'c, I> {
+    cache: &'c mut MemoryCache<I>,
+    namespace: String,
+    session_id: u32,
+    dry_run: bool,
+    /// copy of the namespace changed by the session, applied to the cache by `end_session` so that
+    /// a failed session leaves the cache untouched. `None` for dry runs, which read the cache directly
+    staged: Option<MemoryNamespace<I>>,
+    /// saved along with the staged entries
+    info: SessionInfo,
+    roots: Vec<PathBuf>,
+    /// paths of the entries by inode and device, built by the first `lookup_inode`
+    inodes: Option<HashMap<(u64, u64), Vec<PathBuf>>>,
+}
+
+impl<'c, I: Clone> MemoizedFsCache<I> for &'c mut MemoryCache<I> {
+    type Session = MemoryCacheSession<'c, I>;
+
+    fn start_session(self, namespace: &str) -> Result<Self::Session> {
+        let session_id = self.next_session_id();
+        let staged = self.namespaces.get(namespace).cloned().unwrap_or_default();
+        Ok(MemoryCacheSession {
+            cache: self,
+            namespace: namespace.to_string(),
+            session_id,
+            dry_run: false,
+            staged: Some(staged),
+            info: empty_session_info(session_id, namespace),
+            roots: Vec::new(),
+            inodes: None,
+        })
+    }
+
+    fn start_dry_run_session(self, namespace: &str) -> Result<Self::Session> {
+        self.namespaces.entry(namespace.to_string()).or_default();
+        let session_id = self.next_session_id();
+        Ok(MemoryCacheSession {
+            cache: self,
+            namespace: namespace.to_string(),
+            session_id,
+            dry_run: true,
+            staged: None,
+            info: empty_session_info(session_id, namespace),
+            roots: Vec::new(),
+            inodes: None,
+        })
+    }
+}
+
+/// replaced by `record_session` when the session ends
+fn empty_session_info(session_id: u32, namespace: &str) -> SessionInfo {
+    SessionInfo {
+        session_id,
+        namespace: namespace.to_string(),
+        started_at: SystemTime::now(),
+        ended_at: None,
+        hostname: None,
+        roots: Vec::new(),
+        label: None,
+        processor: None,
+        artifact: None,
+    }
+}
+
+impl<'c, I> MemoryCacheSession<'c, I> {
+    fn entries(&mut self) -> &mut MemoryNamespace<I> {
+        match &mut self.staged {
+            Some(staged) => staged,
+            None => self
+                .cache
+                .namespaces
+                .get_mut(&self.namespace)
+                .expect("namespace created when starting the session"),
+        }
+    }
+}
+
+impl<'c, I: Clone> MemoizedFsCacheSession<I> for MemoryCacheSession<'c, I> {
+    type Cache = &'c mut MemoryCache<I>;
+
+    fn lookup_entry(
+        &mut self,
+        path: &Path,
+        stat: &FsStat,
+        detector: ChangeDetector,
+        force_update: bool,
+    ) -> Result<CacheLookup<I>> {
+        let (session_id, dry_run) = (self.session_id, self.dry_run);
+        match self.entries().entries.get_mut(path) {
+            Some(entry) if !force_update && detector.is_unchanged(&entry.stat, stat) => {
+                //entry has not changed, retrun back the item
+                if !dry_run {
+                    entry.last_seen_session = session_id;
+                }
+                Ok(CacheLookup::Hit(FsEntry {
+                    item: entry.item.clone(),
+                    mtime: entry.stat.mtime,
+                }))
+            }
+            Some(entry) => Ok(CacheLookup::Miss(Some(entry.item.clone()))),
+            None => Ok(CacheLookup::Miss(None)),
+        }
+    }
+
+    fn has_entry(&mut self, path: &Path) -> Result<bool> {
+        Ok(self.entries().entries.contains_key(path))
+    }
+
+    fn keep_entry(&mut self, path: &Path) -> Result<Option<FsEntry<I>>> {
+        let (session_id, dry_run) = (self.session_id, self.dry_run);
+        let entries = self.entries();
+        if !dry_run {
+            for (_, entry) in entries.subtree_mut(path) {
+                entry.last_seen_session = session_id;
+            }
+        }
+        Ok(entries.entries.get(path).map(|entry| FsEntry {
+            item: entry.item.clone(),
+            mtime: entry.stat.mtime,
+        }))
+    }
+
+    fn store_entry(&mut self, path: &Path, stat: &FsStat, item: I) -> Result<FsEntry<I>> {
+        if self.dry_run {
+            return Ok(FsEntry {
+                item,
+                mtime: stat.mtime,
+            });
+        }
+        let session_id = self.session_id;
+        let entries = self.entries();
+        let previous = entries.entries.insert(
+            path.to_path_buf(),
+            MemoryEntry {
+                item: item.clone(),
+                stat: stat.clone(),
+                session_id,
+                last_seen_session: session_id,
+            },
+        );
+        // keep the state of previous sessions for rollback
+        if let Some(previous) = previous.filter(|previous| previous.session_id < session_id) {
+            entries.history.push(MemoryHistoryEntry {
+                path: path.to_path_buf(),
+                entry: previous,
+                replaced_session_id: session_id,
+            });
+        }
+        Ok(FsEntry {
+            item,
+            mtime: stat.mtime,
+        })
+    }
+
+    fn lookup_inode(&mut self, stat: &FsStat) -> Result<Vec<PathBuf>> {
+        let key = (stat.inode, stat.device);
+        if self.inodes.is_none() {
+            let mut inodes: HashMap<_, Vec<_>> = HashMap::new();
+            for (path, entry) in &self.entries().entries {
+                inodes
+                    .entry((entry.stat.inode, entry.stat.device))
+                    .or_default()
+                    .push(path.clone());
+            }
+            self.inodes = Some(inodes);
+        }
+        let candidates = self.inodes.as_ref().and_then(|inodes| inodes.get(&key));
+        let candidates = candidates.cloned().unwrap_or_default();
+        // the index is not updated by the session, check the entries are still there
+        let entries = self.entries();
+        Ok(candidates
+            .into_iter()
+            .filter(|path| {
+                entries
+                    .entries
+                    .get(path)
+                    .is_some_and(|entry| (entry.stat.inode, entry.stat.device) == key)
+            })
+            .collect())
+    }
+
+    fn move_entries(&mut self, from: &Path, to: &Path) -> Result<Option<FsEntry<I>>> {
+        if self.dry_run {
+            return Ok(None);
+        }
+        let session_id = self.session_id;
+        let entries = self.entries();
+        for path in entries.subtree_paths(to) {
+            entries.remove_entry(&path, session_id);
+        }
+        for path in entries.subtree_paths(from) {
+            if let Some(mut entry) = entries.remove_entry(&path, session_id) {
+                entry.session_id = session_id;
+                let relative = path.strip_prefix(from)?;
+                let moved_path = if relative.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(relative)
+                };
+                entries.entries.insert(moved_path, entry);
+            }
+        }
+        Ok(entries.entries.get(to).map(|entry| FsEntry {
+            item: entry.item.clone(),
+            mtime: entry.stat.mtime,
+        }))
+    }
+
+    fn list_descendants(
+        &mut self,
+        path: &Path,
+        kept_children: &HashSet<PathBuf>,
+    ) -> Result<Vec<(PathBuf, I)>> {
+        let entries = &self.entries().entries;
+        Ok(entries
+            .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+            .take_while(|(entry_path, _)| entry_path.starts_with(path))
+            .filter(|(entry_path, _)| is_removed_descendant(path, entry_path, kept_children))
+            .map(|(entry_path, entry)| (entry_path.clone(), entry.item.clone()))
+            .collect())
+    }
+
+    fn add_root(&mut self, path: &Path) -> Result<()> {
+        self.roots.push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn record_session(&mut self, info: &SessionInfo) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.info = SessionInfo {
+            session_id: self.session_id,
+            namespace: self.namespace.clone(),
+            ..info.clone()
+        };
+        Ok(())
+    }
+
+    fn end_session(mut self) -> Result<Self::Cache> {
+        if self.dry_run {
+            return Ok(self.cache);
+        }
+        // entries not seen by this session under its roots were removed, the other ones belong to other scopes
+        let session_id = self.session_id;
+        let roots = std::mem::take(&mut self.roots);
+        let entries = self.entries();
+        for root in &roots {
+            let removed: Vec<PathBuf> = entries
+                .subtree_mut(root)
+                .filter(|(_, entry)| entry.last_seen_session < session_id)
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in removed {
+                if let Some(entry) = entries.entries.remove(&path) {
+                    entries.history.push(MemoryHistoryEntry {
+                        path,
+                        entry,
+                        replaced_session_id: session_id,
+                    });
+                }
+            }
+        }
+        let staged = self
+            .staged
+            .take()
+            .expect("only dry runs have no staged entries");
+        self.cache.namespaces.insert(self.namespace, staged);
+        self.cache.sessions.push(self.info);
+        Ok(self.cache)
+    }
+
+    fn get_id(&self) -> u32 {
+        self.session_id
+    }
+}
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+
+/// lossless bytes of a path on unix, its utf-8 form elsewhere
+pub(crate) fn path_to_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+        PathBuf::from(OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// bounds of the paths below `path`: the ones between "<path>/" and "<path>0", '0' being the character after '/'
+#[cfg(any(feature = "sqlite", feature = "redb"))]
+pub(crate) fn children_range(path: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    // without its trailing slash, the children of "/" are the paths between "/" and "0"
+    let mut prefix = path;
+    while let [rest @ .., b'/'] = prefix {
+        prefix = rest;
+    }
+    let mut low = prefix.to_vec();
+    low.push(b'/');
+    let mut high = prefix.to_vec();
+    high.push(b'0');
+    (low, high)
+}
+
+/// serde helper storing the children of a folder as `(name bytes, value)` pairs,
+/// names that are not valid utf-8 can then be used with any serializer
+#[cfg(feature = "serde")]
+pub(crate) mod children_map {
+    use std::{iter::FromIterator, path::PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{path_from_bytes, path_to_bytes};
+
+    pub(crate) fn serialize<'a, M, V, S>(map: &'a M, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        &'a M: IntoIterator<Item = (&'a PathBuf, &'a V)>,
+        V: Serialize + 'a,
+        S: Serializer,
+    {
+        serializer.collect_seq(
+            map.into_iter()
+                .map(|(name, value)| (path_to_bytes(name), value)),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, M, V, D>(deserializer: D) -> Result<M, D::Error>
+    where
+        M: FromIterator<(PathBuf, V)>,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs = Vec::<(Vec<u8>, V)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(name, value)| (path_from_bytes(name), value))
+            .collect())
+    }
+}
+
+/// serde helper storing a path as its bytes
+#[cfg(feature = "serde")]
+pub(crate) mod path {
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{path_from_bytes, path_to_bytes};
+
+    pub(crate) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        path_to_bytes(path).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PathBuf, D::Error> {
+        Ok(path_from_bytes(Vec::<u8>::deserialize(deserializer)?))
+    }
+}
+
+/// serde helper storing `(path, mount path)` pairs as bytes
+#[cfg(feature = "serde")]
+pub(crate) mod path_pairs {
+    use std::path::PathBuf;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{path_from_bytes, path_to_bytes};
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(PathBuf, PathBuf)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            pairs
+                .iter()
+                .map(|(path, mount_path)| (path_to_bytes(path), path_to_bytes(mount_path))),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, D::Error> {
+        let pairs = Vec::<(Vec<u8>, Vec<u8>)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(path, mount_path)| (path_from_bytes(path), path_from_bytes(mount_path)))
+            .collect())
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use redb_impl::{
+    Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    memoized::is_removed_descendant,
+    path_bytes::{children_range, path_from_bytes, path_to_bytes},
+    CacheLookup, ChangeDetector, FsEntry, FsStat, ItemCodec, MemoizedFsCache,
+    MemoizedFsCacheSession, SessionInfo,
+};
+
+// key: namespace length (u32 big endian), namespace, path bytes; the entries below a path follow it
+// value: last seen session (u32 little endian), session id (u32 little endian), `Record` encoded with the item codec
+const CACHE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("fs_walker_cache");
+// key: replaced session id (u32 big endian), cache key; value: the cache value without the last seen session
+const HISTORY: TableDefinition<&[u8], &[u8]> = TableDefinition::new("fs_walker_cache_history");
+// value: bincode `SessionInfo`
+const SESSIONS: TableDefinition<u32, &[u8]> = TableDefinition::new("fs_walker_sessions");
+const SETTINGS: TableDefinition<&str, &str> = TableDefinition::new("fs_walker_settings");
+
+const HEADER_LEN: usize = 8;
+
+/// cache keys by inode and device
+type InodeIndex = HashMap<(u64, u64), Vec<Vec<u8>>>;
+
+#[derive(Serialize, Deserialize)]
+struct Record<I> {
+    stat: FsStat,
+    item: I,
+}
+
+/// `Record` without decoding its item
+#[derive(Deserialize)]
+struct RecordStat {
+    stat: FsStat,
+}
+
+/// Cache stored in a redb database, the entries of a folder are next to each other so a subtree is one range scan
+pub struct RedbCache {
+    db: Database,
+    codec: ItemCodec,
+}
+
+impl RedbCache {
+    /// Open the database at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(CACHE)?;
+        txn.open_table(HISTORY)?;
+        txn.open_table(SESSIONS)?;
+        let codec = {
+            let mut settings = txn.open_table(SETTINGS)?;
+            let name = settings
+                .get("item_codec")?
+                .map(|name| name.value().to_string());
+            match name {
+                Some(name) => ItemCodec::from_name(&name)?,
+                None => {
+                    settings.insert("item_codec", ItemCodec::default().name())?;
+                    ItemCodec::default()
+                }
+            }
+        };
+        txn.commit()?;
+        Ok(Self { db, codec })
+    }
+
+    /// Encoding of the items saved in this cache, `ItemCodec::default()` for new databases
+    pub fn item_codec(&self) -> ItemCodec {
+        self.codec
+    }
+
+    /// Choose the encoding of the items, only possible while no item is saved
+    pub fn set_item_codec(&mut self, codec: ItemCodec) -> Result<()> {
+        if codec == self.codec {
+            return Ok(());
+        }
+        let txn = self.db.begin_write()?;
+        if !txn.open_table(CACHE)?.is_empty()? || !txn.open_table(HISTORY)?.is_empty()? {
+            bail!(
+                "the items of the cache are encoded with {}, the codec can only be changed while the cache is empty",
+                self.codec.name()
+            );
+        }
+        txn.open_table(SETTINGS)?
+            .insert("item_codec", codec.name())?;
+        txn.commit()?;
+        self.codec = codec;
+        Ok(())
+    }
+
+    /// Restore the cache of `namespace` as it was at the end of its last session before `id`,
+    /// forgetting the sessions of this namespace from `id`
+    pub fn rollback_before_session_id(&self, namespace: &str, id: u32) -> Result<()> {
+        let prefix = namespace_prefix(namespace);
+        let last_seen = id.saturating_sub(1).to_le_bytes();
+        let txn = self.db.begin_write()?;
+        {
+            let mut cache = txn.open_table(CACHE)?;
+            cache.retain_in(prefix.as_slice().., |key, value| {
+                !key.starts_with(&prefix) || value_session_id(value) < id
+            })?;
+            // every remaining entry was part of the cache at the end of the session `id - 1`
+            let seen_since: Vec<(Vec<u8>, Vec<u8>)> = cache
+                .range(prefix.as_slice()..)?
+                .map(|entry| {
+                    entry.map(|(key, value)| (key.value().to_vec(), value.value().to_vec()))
+                })
+                .take_while(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key.starts_with(&prefix))
+                })
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(_, value)| value_last_seen(value) >= id)
+                })
+                .collect::<Result<_, _>>()?;
+            for (key, mut value) in seen_since {
+                value[..4].copy_from_slice(&last_seen);
+                cache.insert(key.as_slice(), value.as_slice())?;
+            }
+
+            // entries written before `id` and replaced or removed since
+            let mut history = txn.open_table(HISTORY)?;
+            let from = id.to_be_bytes();
+            let replaced: Vec<(Vec<u8>, Vec<u8>)> = history
+                .range(&from[..]..)?
+                .map(|entry| {
+                    entry.map(|(key, value)| (key.value().to_vec(), value.value().to_vec()))
+                })
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |(key, _)| key[4..].starts_with(&prefix))
+                })
+                .collect::<Result<_, _>>()?;
+            for (key, value) in replaced {
+                history.remove(key.as_slice())?;
+                if u32::from_le_bytes(value[..4].try_into()?) < id {
+                    let mut restored = last_seen.to_vec();
+                    restored.extend_from_slice(&value);
+                    cache.insert(&key[4..], restored.as_slice())?;
+                }
+            }
+
+            let mut sessions = txn.open_table(SESSIONS)?;
+            let mut forgotten = Vec::new();
+            for entry in sessions.range(id..)? {
+                let (session_id, info) = entry?;
+                if bincode::deserialize::<SessionInfo>(info.value())?.namespace == namespace {
+                    forgotten.push(session_id.value());
+                }
+            }
+            for session_id in forgotten {
+                sessions.remove(session_id)?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Forget the history needed to rollback before the session `id`, `rollback_before_session_id`
+    /// can then only restore the session `id - 1` or a later one
+    pub fn prune_history_before_session_id(&self, id: u32) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(HISTORY)?
+            .retain_in(..&id.to_be_bytes()[..], |_, _| false)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Sessions saved in the cache, from the oldest to the latest
+    pub fn list_sessions(&self) -> Result<Vec<SessionInfo>> {
+        let txn = self.db.begin_read()?;
+        let sessions = txn.open_table(SESSIONS)?;
+        let mut infos = Vec::new();
+        for entry in sessions.iter()? {
+            infos.push(bincode::deserialize(entry?.1.value())?);
+        }
+        Ok(infos)
+    }
+}
+
+fn namespace_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = (namespace.len() as u32).to_be_bytes().to_vec();
+    prefix.extend_from_slice(namespace.as_bytes());
+    prefix
+}
+
+fn value_last_seen(value: &[u8]) -> u32 {
+    u32::from_le_bytes([value[0], value[1], value[2], value[3]])
+}
+
+fn value_session_id(value: &[u8]) -> u32 {
+    u32::from_le_bytes([value[4], value[5], value[6], value[7]])
+}
+
+fn next_session_id(txn: &WriteTransaction) -> Result<u32> {
+    let sessions = txn.open_table(SESSIONS)?;
+    let last = sessions.last()?.map(|(session_id, _)| session_id.value());
+    Ok(last.unwrap_or(0) + 1)
+}
+
+pub struct RedbSession<'c> {
+    cache: &'c RedbCache,
+    // dropped without being committed by dry runs
+    txn: WriteTransaction,
+    namespace: String,
+    prefix: Vec<u8>,
+    session_id: u32,
+    dry_run: bool,
+    roots: Vec<PathBuf>,
+    /// built by the first `lookup_inode`
+    inodes: Option<InodeIndex>,
+}
+
+impl<'c> RedbSession<'c> {
+    fn key(&self, path: &Path) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend_from_slice(&path_to_bytes(path));
+        key
+    }
+
+    /// keys and values of `path` and of the entries below it
+    fn subtree(&self, path: &Path) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let key = self.key(path);
+        let (children_low, children_high) = children_range(&path_to_bytes(path));
+        let (mut low, mut high) = (self.prefix.clone(), self.prefix.clone());
+        low.extend_from_slice(&children_low);
+        high.extend_from_slice(&children_high);
+
+        let cache = self.txn.open_table(CACHE)?;
+        let mut entries = Vec::new();
+        if let Some(value) = cache.get(key.as_slice())? {
+            entries.push((key.clone(), value.value().to_vec()));
+        }
+        for entry in cache.range(low.as_slice()..high.as_slice())? {
+            let (key, value) = entry?;
+            entries.push((key.value().to_vec(), value.value().to_vec()));
+        }
+        Ok(entries)
+    }
+}
+
+impl<'c, I: Serialize + DeserializeOwned> MemoizedFsCache<I> for &'c RedbCache {
+    type Session = RedbSession<'c>;
+
+    fn start_session(self, namespace: &str) -> Result<Self::Session> {
+        let txn = self.db.begin_write()?;
+        let session_id = next_session_id(&txn)?;
+        // replaced by `record_session` when the session ends
+        let info = SessionInfo {
+            session_id,
+            namespace: namespace.to_string(),
+            started_at: SystemTime::now(),
+            ended_at: None,
+            hostname: None,
+            roots: Vec::new(),
+            label: None,
+            processor: None,
+            artifact: None,
+        };
+        txn.open_table(SESSIONS)?
+            .insert(session_id, bincode::serialize(&info)?.as_slice())?;
+        Ok(RedbSession {
+            cache: self,
+            txn,
+            namespace: namespace.to_string(),
+            prefix: namespace_prefix(namespace),
+            session_id,
+            dry_run: false,
+            roots: Vec::new(),
+            inodes: None,
+        })
+    }
+
+    fn start_dry_run_session(self, namespace: &str) -> Result<Self::Session> {
+        let txn = self.db.begin_write()?;
+        Ok(RedbSession {
+            cache: self,
+            session_id: next_session_id(&txn)?,
+            txn,
+            namespace: namespace.to_string(),
+            prefix: namespace_prefix(namespace),
+            dry_run: true,
+            roots: Vec::new(),
+            inodes: None,
+        })
+    }
+}
+
+impl<'c, I: Serialize + DeserializeOwned> MemoizedFsCacheSession<I> for RedbSession<'c> {
+    type Cache = &'c RedbCache;
+
+    fn lookup_entry(
+        &mut self,
+        path: &Path,
+        stat: &FsStat,
+        detector: ChangeDetector,
+        force_update: bool,
+    ) -> Result<CacheLookup<I>> {
+        let key = self.key(path);
+        let mut cache = self.txn.open_table(CACHE)?;
+        let mut value = match cache.get(key.as_slice())? {
+            Some(value) => value.value().to_vec(),
+            None => return Ok(CacheLookup::Miss(None)),
+        };
+        let record: Record<I> = self.cache.codec.decode(&value[HEADER_LEN..])?;
+        if force_update || !detector.is_unchanged(&record.stat, stat) {
+            return Ok(CacheLookup::Miss(Some(record.item)));
+        }
+        //entry has not changed, retrun back the item
+        if !self.dry_run && value_last_seen(&value) != self.session_id {
+            value[..4].copy_from_slice(&self.session_id.to_le_bytes());
+            cache.insert(key.as_slice(), value.as_slice())?;
+        }
+        Ok(CacheLookup::Hit(FsEntry {
+            item: record.item,
+            mtime: record.stat.mtime,
+        }))
+    }
+
+    fn has_entry(&mut self, path: &Path) -> Result<bool> {
+        let cache = self.txn.open_table(CACHE)?;
+        let exists = cache.get(self.key(path).as_slice())?.is_some();
+        Ok(exists)
+    }
+
+    fn keep_entry(&mut self, path: &Path) -> Result<Option<FsEntry<I>>> {
+        let key = self.key(path);
+        let entries = self.subtree(path)?;
+        let mut kept = None;
+        let mut cache = self.txn.open_table(CACHE)?;
+        for (entry_key, mut value) in entries {
+            if entry_key == key {
+                let record: Record<I> = self.cache.codec.decode(&value[HEADER_LEN..])?;
+                kept = Some(FsEntry {
+                    item: record.item,
+                    mtime: record.stat.mtime,
+                });
+            }
+            if !self.dry_run && value_last_seen(&value) != self.session_id {
+                value[..4].copy_from_slice(&self.session_id.to_le_bytes());
+                cache.insert(entry_key.as_slice(), value.as_slice())?;
+            }
+        }
+        Ok(kept)
+    }
+
+    fn store_entry(&mut self, path: &Path, stat: &FsStat, item: I) -> Result<FsEntry<I>> {
+        let record = Record {
+            stat: stat.clone(),
+            item,
+        };
+        if !self.dry_run {
+            let key = self.key(path);
+            let mut value = Vec::with_capacity(HEADER_LEN + 64);
+            value.extend_from_slice(&self.session_id.to_le_bytes());
+            value.extend_from_slice(&self.session_id.to_le_bytes());
+            value.extend_from_slice(&self.cache.codec.encode(&record)?);
+
+            let mut cache = self.txn.open_table(CACHE)?;
+            let previous = cache
+                .insert(key.as_slice(), value.as_slice())?
+                .map(|previous| previous.value().to_vec());
+            drop(cache);
+            // keep the state of previous sessions for rollback
+            if let Some(previous) =
+                previous.filter(|previous| value_session_id(previous) < self.session_id)
+            {
+                let mut history_key = self.session_id.to_be_bytes().to_vec();
+                history_key.extend_from_slice(&key);
+                self.txn
+                    .open_table(HISTORY)?
+                    .insert(history_key.as_slice(), &previous[4..])?;
+            }
+        }
+        Ok(FsEntry {
+            item: record.item,
+            mtime: stat.mtime,
+        })
+    }
+
+    fn lookup_inode(&mut self, stat: &FsStat) -> Result<Vec<PathBuf>> {
+        let cache = self.txn.open_table(CACHE)?;
+        if self.inodes.is_none() {
+            let mut inodes: HashMap<_, Vec<_>> = HashMap::new();
+            for entry in cache.range(self.prefix.as_slice()..)? {
+                let (key, value) = entry?;
+                if !key.value().starts_with(&self.prefix) {
+                    break;
+                }
+                let record: RecordStat = self.cache.codec.decode(&value.value()[HEADER_LEN..])?;
+                inodes
+                    .entry((record.stat.inode, record.stat.device))
+                    .or_default()
+                    .push(key.value().to_vec());
+            }
+            self.inodes = Some(inodes);
+        }
+        let key = (stat.inode, stat.device);
+        let candidates = self.inodes.as_ref().and_then(|inodes| inodes.get(&key));
+        let mut paths = Vec::new();
+        // the index is not updated by the session, check the entries are still there
+        for candidate in candidates.into_iter().flatten() {
+            if let Some(value) = cache.get(candidate.as_slice())? {
+                let record: RecordStat = self.cache.codec.decode(&value.value()[HEADER_LEN..])?;
+                if (record.stat.inode, record.stat.device) == key {
+                    paths.push(path_from_bytes(candidate[self.prefix.len()..].to_vec()));
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    fn move_entries(&mut self, from: &Path, to: &Path) -> Result<Option<FsEntry<I>>> {
+        if self.dry_run {
+            return Ok(None);
+        }
+        let (from_key, to_key) = (self.key(from), self.key(to));
+        let replaced = self.subtree(to)?;
+        let moved = self.subtree(from)?;
+        let mut cache = self.txn.open_table(CACHE)?;
+        let mut history = self.txn.open_table(HISTORY)?;
+        // the entries below `to` are replaced, then the moved ones leave `from`, both are kept for rollback
+        for (key, value) in replaced.iter().chain(&moved) {
+            cache.remove(key.as_slice())?;
+            if value_session_id(value) < self.session_id {
+                let mut history_key = self.session_id.to_be_bytes().to_vec();
+                history_key.extend_from_slice(key);
+                history.insert(history_key.as_slice(), &value[4..])?;
+            }
+        }
+        let mut moved_entry = None;
+        for (key, mut value) in moved {
+            let mut moved_key = to_key.clone();
+            moved_key.extend_from_slice(&key[from_key.len()..]);
+            value[4..HEADER_LEN].copy_from_slice(&self.session_id.to_le_bytes());
+            if moved_key == to_key {
+                let record: Record<I> = self.cache.codec.decode(&value[HEADER_LEN..])?;
+                moved_entry = Some(FsEntry {
+                    item: record.item,
+                    mtime: record.stat.mtime,
+                });
+            }
+            cache.insert(moved_key.as_slice(), value.as_slice())?;
+        }
+        Ok(moved_entry)
+    }
+
+    fn list_descendants(
+        &mut self,
+        path: &Path,
+        kept_children: &HashSet<PathBuf>,
+    ) -> Result<Vec<(PathBuf, I)>> {
+        let mut descendants = Vec::new();
+        for (key, value) in self.subtree(path)? {
+            let descendant = path_from_bytes(key[self.prefix.len()..].to_vec());
+            if is_removed_descendant(path, &descendant, kept_children) {
+                let record: Record<I> = self.cache.codec.decode(&value[HEADER_LEN..])?;
+                descendants.push((descendant, record.item));
+            }
+        }
+        Ok(descendants)
+    }
+
+    fn add_root(&mut self, path: &Path) -> Result<()> {
+        self.roots.push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn record_session(&mut self, info: &SessionInfo) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let info = SessionInfo {
+            session_id: self.session_id,
+            namespace: self.namespace.clone(),
+            ..info.clone()
+        };
+        self.txn
+            .open_table(SESSIONS)?
+            .insert(self.session_id, bincode::serialize(&info)?.as_slice())?;
+        Ok(())
+    }
+
+    fn end_session(self) -> Result<Self::Cache> {
+        if self.dry_run {
+            return Ok(self.cache);
+        }
+        // entries not seen by this session under its roots were removed, the other ones belong to other scopes
+        for root in &self.roots {
+            let removed: Vec<(Vec<u8>, Vec<u8>)> = self
+                .subtree(root)?
+                .into_iter()
+                .filter(|(_, value)| value_last_seen(value) < self.session_id)
+                .collect();
+            let mut cache = self.txn.open_table(CACHE)?;
+            let mut history = self.txn.open_table(HISTORY)?;
+            for (key, value) in removed {
+                cache.remove(key.as_slice())?;
+                let mut history_key = self.session_id.to_be_bytes().to_vec();
+                history_key.extend_from_slice(&key);
+                history.insert(history_key.as_slice(), &value[4..])?;
+            }
+        }
+        self.txn.commit()?;
+        Ok(self.cache)
+    }
+
+    fn get_id(&self) -> u32 {
+        self.session_id
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use crate::{FsStat, WalkError};
+
+/// Number of entries of a kind seen during a session
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntryCounts {
+    pub added: u64,
+    /// content or metadata changed
+    pub changed: u64,
+    pub removed: u64,
+    /// found at a new path, see `MemoizedFsWalker::detect_moves`
+    pub moved: u64,
+    pub unchanged: u64,
+}
+
+/// Entries seen during a session, by kind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChangeCounts {
+    pub files: EntryCounts,
+    pub symlinks: EntryCounts,
+    pub folders: EntryCounts,
+    pub specials: EntryCounts,
+}
+
+/// Summary of a session, returned by `MemoizedFsWalkerSession::finish_processing`
+///
+/// Removed entries are only counted by processors tracking them, like `ChangeNotifier`
+#[derive(Debug, Default)]
+pub struct SessionReport {
+    pub session_id: u32,
+    pub changes: ChangeCounts,
+    /// size of the files hashed by the change detector, plus the bytes the processor reported reading
+    /// from `FsProcessor::fill_report`, like the files archived by `TarProcessor`
+    pub bytes_read: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub elapsed: Duration,
+    /// entries skipped because of an error, see `MemoizedFsWalker::tolerate_errors`
+    pub errors: Vec<WalkError>,
+}
+
+impl SessionReport {
+    /// true if at least one entry was added, changed, removed or moved
+    pub fn has_changes(&self) -> bool {
+        let ChangeCounts {
+            files,
+            symlinks,
+            folders,
+            specials,
+        } = &self.changes;
+        [files, symlinks, folders, specials]
+            .iter()
+            .any(|counts| counts.added + counts.changed + counts.removed + counts.moved > 0)
+    }
+
+    /// count a cache hit when `had_previous` is `None`, an added or changed entry otherwise
+    pub(crate) fn count(
+        &mut self,
+        counts: fn(&mut ChangeCounts) -> &mut EntryCounts,
+        had_previous: Option<bool>,
+    ) {
+        let counts = counts(&mut self.changes);
+        match had_previous {
+            None => {
+                counts.unchanged += 1;
+                self.cache_hits += 1;
+            }
+            Some(had_previous) => {
+                if had_previous {
+                    counts.changed += 1;
+                } else {
+                    counts.added += 1;
+                }
+                self.cache_misses += 1;
+            }
+        }
+    }
+
+    /// count the bytes of a file read by the change detector to hash it
+    pub(crate) fn count_hashed(&mut self, stat: &FsStat) {
+        if stat.content_hash.is_some() {
+            self.bytes_read += stat.size;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::{path::PathBuf, time::SystemTime};
+
+/// Description of a session, saved by the cache when the session ends
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionInfo {
+    pub session_id: u32,
+    /// see `MemoizedFsWalker::namespace`
+    pub namespace: String,
+    pub started_at: SystemTime,
+    /// `None` while the session is running, or if it was never finished
+    pub ended_at: Option<SystemTime>,
+    pub hostname: Option<String>,
+    /// paths given to `add_path` along with their mount paths
+    #[cfg_attr(feature = "serde", serde(with = "crate::path_bytes::path_pairs"))]
+    pub roots: Vec<(PathBuf, PathBuf)>,
+    pub label: Option<String>,
+    /// see `FsProcessor::kind`
+    pub processor: Option<String>,
+    /// what was produced by the session, like the path or the checksum of a tar file
+    pub artifact: Option<String>,
+}
+
+impl SessionInfo {
+    pub(crate) fn new(namespace: &str, started_at: SystemTime, processor: &str) -> Self {
+        Self {
+            session_id: 0,
+            namespace: namespace.to_string(),
+            started_at,
+            ended_at: None,
+            hostname: hostname(),
+            roots: Vec::new(),
+            label: None,
+            processor: Some(processor.to_string()),
+            artifact: None,
+        }
+    }
+}
+
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|file| std::fs::read_to_string(file).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
@@ -1,156 +1,479 @@
-use anyhow::Result;
-use rusqlite::{
-    params,
-    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
-    Connection, ToSql,
-};
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    path::Path,
+    collections::HashSet,
+    path::{Path, PathBuf},
     time::{Duration, SystemTime},
 };
 
-use crate::MemoizedFsCache;
+use crate::{
+    memoized::is_removed_descendant,
+    path_bytes::{children_range, path_from_bytes, path_to_bytes},
+    CacheLookup, ChangeDetector, FsStat, ItemCodec, MemoizedFsCache, SessionInfo,
+};
 
 use super::{FsEntry, MemoizedFsCacheSession};
 
 pub struct SqliteSycnSession<'c> {
     db: &'c Connection,
+    namespace: String,
     session_id: u32,
+    dry_run: bool,
+    roots: Vec<PathBuf>,
+    codec: ItemCodec,
 }
 
-impl<'c, I: ToSql + FromSql> MemoizedFsCache<I> for &'c Connection {
+impl<'c, I: Serialize + DeserializeOwned> MemoizedFsCache<I> for &'c Connection {
     type Session = SqliteSycnSession<'c>;
 
-    fn start_session(self) -> Result<Self::Session> {
-        self.execute(
-            r#"
-        CREATE TEMP TABLE fs_walker_last_session_seen_rows (
-            cache_row ROWID NOT NULL PRIMARY KEY
-        );
-        "#,
-            [],
-        )?;
-
+    fn start_session(self, namespace: &str) -> Result<Self::Session> {
+        check_schema_version(self)?;
+        let session_id = next_session_id(self)?;
         let mut stmt = self.prepare_cached(
             r#"
-        SELECT MAX(session_id) FROM fs_walker_sessions;
+        INSERT INTO fs_walker_sessions (session_id, namespace) VALUES(?1, ?2)
         "#,
         )?;
-        let opt_max_session_id = stmt.query_row(params![], |row| row.get::<_, Option<_>>(0))?;
-        let session_id = opt_max_session_id.unwrap_or(0) + 1;
-        let mut stmt = self.prepare_cached(
-            r#"
-        INSERT INTO fs_walker_sessions (session_id) VALUES(?1)
-        "#,
-        )?;
-        stmt.execute(params![session_id])?;
+        stmt.execute(params![session_id, namespace])?;
         Ok(SqliteSycnSession {
             db: self,
+            namespace: namespace.to_string(),
             session_id,
+            dry_run: false,
+            roots: Vec::new(),
+            codec: sqlite_item_codec(self)?,
+        })
+    }
+
+    fn start_dry_run_session(self, namespace: &str) -> Result<Self::Session> {
+        check_schema_version(self)?;
+        Ok(SqliteSycnSession {
+            db: self,
+            namespace: namespace.to_string(),
+            session_id: next_session_id(self)?,
+            dry_run: true,
+            roots: Vec::new(),
+            codec: sqlite_item_codec(self)?,
         })
     }
 }
 
-impl<'c, I: ToSql + FromSql> MemoizedFsCacheSession<I> for SqliteSycnSession<'c> {
+fn next_session_id(db: &Connection) -> Result<u32> {
+    let mut stmt = db.prepare_cached(
+        r#"
+    SELECT MAX(session_id) FROM fs_walker_sessions;
+    "#,
+    )?;
+    let opt_max_session_id = stmt.query_row(params![], |row| row.get::<_, Option<u32>>(0))?;
+    Ok(opt_max_session_id.unwrap_or(0) + 1)
+}
+
+impl<'c, I: Serialize + DeserializeOwned> MemoizedFsCacheSession<I> for SqliteSycnSession<'c> {
     type Cache = &'c Connection;
 
-    fn get_update_entry_from_cache<Op>(
+    fn lookup_entry(
         &mut self,
         path: &Path,
-        mtime: SystemTime,
-        compute_item: Op,
-    ) -> Result<FsEntry<I>>
-    where
-        Op: FnOnce(Option<I>) -> Result<I>,
-    {
-        use rusqlite::OptionalExtension;
-
+        stat: &FsStat,
+        detector: ChangeDetector,
+        force_update: bool,
+    ) -> Result<CacheLookup<I>> {
         let mut stmt = self.db.prepare_cached(
             r#"
-        SELECT item, mtime_sec, mtime_nano, rowid FROM fs_walker_cache WHERE path = ?1
+        SELECT item, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size, inode, content_hash, racy, rowid, device
+        FROM fs_walker_cache WHERE namespace = ?1 AND path = ?2
         "#,
         )?;
-        let sql_path = path.to_string_lossy();
-        let sql_mtime_duration = mtime.duration_since(SystemTime::UNIX_EPOCH)?;
-        let sql_mtime_sec = sql_mtime_duration.as_secs();
-        let sql_mtime_nano = sql_mtime_duration.subsec_nanos();
+        let sql_path = path_to_bytes(path);
         let opt_entry = stmt
-            .query_row(params![sql_path], |row| {
-                let item = row.get(0)?;
-                let mtime_sec = row.get(1)?;
-                let mtime_nano = row.get(2)?;
-                let mtime_dur = Duration::new(mtime_sec, mtime_nano);
-                let mtime = SystemTime::UNIX_EPOCH + mtime_dur;
-                Ok((FsEntry { mtime, item }, row.get(3)?))
+            .query_row(params![self.namespace, sql_path], |row| {
+                let item: Vec<u8> = row.get(0)?;
+                let cached = FsStat {
+                    mtime: time_from_sql(row.get(1)?, row.get(2)?),
+                    ctime: time_from_sql(row.get(3)?, row.get(4)?),
+                    size: row.get::<_, i64>(5)? as u64,
+                    inode: row.get::<_, i64>(6)? as u64,
+                    device: row.get::<_, i64>(10)? as u64,
+                    content_hash: row.get(7)?,
+                    racy: row.get(8)?,
+                };
+                Ok((item, cached, row.get::<_, i64>(9)?))
             })
             .optional()?;
-        let out;
-        let row_id;
         match opt_entry {
-            Some((entry, row)) if entry.mtime == mtime => {
+            Some((item, cached, row_id))
+                if !force_update && detector.is_unchanged(&cached, stat) =>
+            {
                 //entry has not changed, retrun back the item
-                out = entry;
-                row_id = row;
-            }
-            Some((entry, row)) => {
-                let item = compute_item(Some(entry.item))?;
-                let mut stmt = self.db.prepare_cached(r#"
-                UPDATE fs_walker_cache SET mtime_sec = ?2, mtime_nano = ?3, session_id = ?4, item = ?5 WHERE path = ?1
-                "#)?;
-
-                stmt.execute(params![
-                    sql_path,
-                    sql_mtime_sec,
-                    sql_mtime_nano,
-                    self.session_id,
-                    item
-                ])?;
-                out = FsEntry { item, mtime };
-                row_id = row;
-            }
-            None => {
-                let item = compute_item(None)?;
-                let mut stmt = self.db.prepare_cached(r#"
-                INSERT INTO fs_walker_cache (path, mtime_sec, mtime_nano, session_id, item) VALUES(?1, ?2, ?3, ?4, ?5)
-                "#)?;
-
-                stmt.execute(params![
-                    sql_path,
-                    sql_mtime_sec,
-                    sql_mtime_nano,
-                    self.session_id,
-                    item
-                ])?;
-                out = FsEntry { item, mtime };
-                row_id = self.db.last_insert_rowid();
+                if !self.dry_run {
+                    let mut stmt = self.db.prepare_cached(
+                        r#"
+                    UPDATE fs_walker_cache SET last_seen_session = ?2 WHERE rowid = ?1
+                    "#,
+                    )?;
+                    stmt.execute(params![row_id, self.session_id])?;
+                }
+                Ok(CacheLookup::Hit(FsEntry {
+                    item: self.codec.decode(&item)?,
+                    mtime: cached.mtime,
+                }))
             }
+            Some((item, _, _)) => Ok(CacheLookup::Miss(Some(self.codec.decode(&item)?))),
+            None => Ok(CacheLookup::Miss(None)),
         }
+    }
+
+    fn has_entry(&mut self, path: &Path) -> Result<bool> {
         let mut stmt = self.db.prepare_cached(
             r#"
-        INSERT INTO fs_walker_last_session_seen_rows (cache_row) VALUES(?1)
+        SELECT EXISTS(SELECT 1 FROM fs_walker_cache WHERE namespace = ?1 AND path = ?2)
         "#,
         )?;
-        stmt.execute(params![row_id])?;
-        Ok(out)
+        let exists = stmt.query_row(params![self.namespace, path_to_bytes(path)], |row| {
+            row.get(0)
+        })?;
+        Ok(exists)
     }
 
-    fn end_session(self) -> Result<Self::Cache> {
-        self.db.execute(
+    fn keep_entry(&mut self, path: &Path) -> Result<Option<FsEntry<I>>> {
+        let sql_path = path_to_bytes(path);
+        let (children_low, children_high) = children_range(&sql_path);
+        if !self.dry_run {
+            let mut stmt = self.db.prepare_cached(
+                r#"
+            UPDATE fs_walker_cache SET last_seen_session = ?2
+            WHERE namespace = ?3 AND (path = ?1 OR (path >= ?4 AND path < ?5))
+            "#,
+            )?;
+            stmt.execute(params![
+                sql_path,
+                self.session_id,
+                self.namespace,
+                children_low,
+                children_high
+            ])?;
+        }
+
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT item, mtime_sec, mtime_nano FROM fs_walker_cache WHERE namespace = ?1 AND path = ?2
+        "#,
+        )?;
+        let opt_entry = stmt
+            .query_row(params![self.namespace, sql_path], |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    time_from_sql(row.get(1)?, row.get(2)?),
+                ))
+            })
+            .optional()?;
+        opt_entry
+            .map(|(item, mtime)| {
+                Ok(FsEntry {
+                    item: self.codec.decode(&item)?,
+                    mtime,
+                })
+            })
+            .transpose()
+    }
+
+    fn store_entry(&mut self, path: &Path, stat: &FsStat, item: I) -> Result<FsEntry<I>> {
+        if self.dry_run {
+            return Ok(FsEntry {
+                item,
+                mtime: stat.mtime,
+            });
+        }
+        let sql_path = path_to_bytes(path);
+        let sql_item = self.codec.encode(&item)?;
+        let (sql_mtime_sec, sql_mtime_nano) = time_to_sql(stat.mtime)?;
+        let (sql_ctime_sec, sql_ctime_nano) = time_to_sql(stat.ctime)?;
+
+        // keep the state of previous sessions for rollback
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        INSERT INTO fs_walker_cache_history (namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+            inode, device, content_hash, racy, session_id, item, replaced_session_id)
+        SELECT namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+            inode, device, content_hash, racy, session_id, item, ?2
+        FROM fs_walker_cache WHERE namespace = ?3 AND path = ?1 AND session_id < ?2
+        "#,
+        )?;
+        stmt.execute(params![sql_path, self.session_id, self.namespace])?;
+
+        let mut stmt = self.db.prepare_cached(
             r#"
-        DELETE FROM fs_walker_cache WHERE rowid NOT IN (
-            SELECT fs_walker_last_session_seen_rows.cache_row 
-            FROM fs_walker_last_session_seen_rows LEFT JOIN fs_walker_cache 
-            ON fs_walker_cache.rowid=fs_walker_last_session_seen_rows.cache_row)
+        UPDATE fs_walker_cache SET mtime_sec = ?2, mtime_nano = ?3, session_id = ?4, last_seen_session = ?4,
+            item = ?5, ctime_sec = ?6, ctime_nano = ?7, size = ?8, inode = ?9, content_hash = ?10, racy = ?11,
+            device = ?13
+        WHERE namespace = ?12 AND path = ?1
         "#,
-            [],
         )?;
+        // sqlite integers are signed, the sizes, inodes and devices above i64::MAX are stored wrapped
+        let updated = stmt.execute(params![
+            sql_path,
+            sql_mtime_sec,
+            sql_mtime_nano,
+            self.session_id,
+            sql_item,
+            sql_ctime_sec,
+            sql_ctime_nano,
+            stat.size as i64,
+            stat.inode as i64,
+            stat.content_hash,
+            stat.racy,
+            self.namespace,
+            stat.device as i64
+        ])?;
+        if updated == 0 {
+            let mut stmt = self.db.prepare_cached(r#"
+            INSERT INTO fs_walker_cache (path, mtime_sec, mtime_nano, session_id, last_seen_session, item, ctime_sec, ctime_nano, size, inode, content_hash, racy, namespace, device)
+            VALUES(?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+            "#)?;
+            stmt.execute(params![
+                sql_path,
+                sql_mtime_sec,
+                sql_mtime_nano,
+                self.session_id,
+                sql_item,
+                sql_ctime_sec,
+                sql_ctime_nano,
+                stat.size as i64,
+                stat.inode as i64,
+                stat.content_hash,
+                stat.racy,
+                self.namespace,
+                stat.device as i64
+            ])?;
+        }
+        Ok(FsEntry {
+            item,
+            mtime: stat.mtime,
+        })
+    }
 
-        self.db.execute(
+    fn lookup_inode(&mut self, stat: &FsStat) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT path FROM fs_walker_cache WHERE namespace = ?1 AND inode = ?2 AND device = ?3
+        "#,
+        )?;
+        let paths = stmt
+            .query_map(
+                params![self.namespace, stat.inode as i64, stat.device as i64],
+                |row| Ok(path_from_bytes(row.get(0)?)),
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+
+    fn move_entries(&mut self, from: &Path, to: &Path) -> Result<Option<FsEntry<I>>> {
+        if self.dry_run {
+            return Ok(None);
+        }
+        let sql_from = path_to_bytes(from);
+        let sql_to = path_to_bytes(to);
+        // the entries below `to` are replaced, then the moved ones leave `from`, both are kept for rollback
+        for sql_path in [&sql_to, &sql_from] {
+            let (children_low, children_high) = children_range(sql_path);
+            let mut stmt = self.db.prepare_cached(
+                r#"
+            INSERT INTO fs_walker_cache_history (namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+                inode, device, content_hash, racy, session_id, item, replaced_session_id)
+            SELECT namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+                inode, device, content_hash, racy, session_id, item, ?1
+            FROM fs_walker_cache
+            WHERE namespace = ?4 AND session_id < ?1 AND (path = ?2 OR (path >= ?3 AND path < ?5))
+            "#,
+            )?;
+            stmt.execute(params![
+                self.session_id,
+                sql_path,
+                children_low,
+                self.namespace,
+                children_high
+            ])?;
+        }
+        let (children_low, children_high) = children_range(&sql_to);
+        let mut stmt = self.db.prepare_cached(
             r#"
-        DROP TABLE fs_walker_last_session_seen_rows;
+        DELETE FROM fs_walker_cache WHERE namespace = ?3 AND (path = ?1 OR (path >= ?2 AND path < ?4))
         "#,
-            [],
         )?;
+        stmt.execute(params![sql_to, children_low, self.namespace, children_high])?;
+
+        let (children_low, children_high) = children_range(&sql_from);
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT rowid, path FROM fs_walker_cache
+        WHERE namespace = ?3 AND (path = ?1 OR (path >= ?2 AND path < ?4))
+        "#,
+        )?;
+        let moved = stmt
+            .query_map(
+                params![sql_from, children_low, self.namespace, children_high],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        UPDATE fs_walker_cache SET path = ?2, session_id = ?3 WHERE rowid = ?1
+        "#,
+        )?;
+        for (row_id, sql_path) in moved {
+            let mut moved_path = sql_to.clone();
+            moved_path.extend_from_slice(&sql_path[sql_from.len()..]);
+            stmt.execute(params![row_id, moved_path, self.session_id])?;
+        }
+
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT item, mtime_sec, mtime_nano FROM fs_walker_cache WHERE namespace = ?1 AND path = ?2
+        "#,
+        )?;
+        let opt_entry = stmt
+            .query_row(params![self.namespace, sql_to], |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    time_from_sql(row.get(1)?, row.get(2)?),
+                ))
+            })
+            .optional()?;
+        opt_entry
+            .map(|(item, mtime)| {
+                Ok(FsEntry {
+                    item: self.codec.decode(&item)?,
+                    mtime,
+                })
+            })
+            .transpose()
+    }
+
+    fn list_descendants(
+        &mut self,
+        path: &Path,
+        kept_children: &HashSet<PathBuf>,
+    ) -> Result<Vec<(PathBuf, I)>> {
+        let (children_low, children_high) = children_range(&path_to_bytes(path));
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT path FROM fs_walker_cache WHERE namespace = ?1 AND path >= ?2 AND path < ?3
+        "#,
+        )?;
+        let paths = stmt
+            .query_map(
+                params![self.namespace, children_low, children_high],
+                |row| Ok(path_from_bytes(row.get(0)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        // only the removed items are read and decoded
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        SELECT item FROM fs_walker_cache WHERE namespace = ?1 AND path = ?2
+        "#,
+        )?;
+        let mut descendants = Vec::new();
+        for descendant in paths {
+            if is_removed_descendant(path, &descendant, kept_children) {
+                let item: Vec<u8> = stmt
+                    .query_row(params![self.namespace, path_to_bytes(&descendant)], |row| {
+                        row.get(0)
+                    })?;
+                descendants.push((descendant, self.codec.decode(&item)?));
+            }
+        }
+        Ok(descendants)
+    }
+
+    fn add_root(&mut self, path: &Path) -> Result<()> {
+        self.roots.push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn record_session(&mut self, info: &SessionInfo) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let (started_sec, started_nano) = time_to_sql(info.started_at)?;
+        let ended_at = info.ended_at.map(time_to_sql).transpose()?;
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        UPDATE fs_walker_sessions SET started_sec = ?2, started_nano = ?3, ended_sec = ?4, ended_nano = ?5,
+            hostname = ?6, label = ?7, processor = ?8, artifact = ?9
+        WHERE session_id = ?1
+        "#,
+        )?;
+        stmt.execute(params![
+            self.session_id,
+            started_sec,
+            started_nano,
+            ended_at.map(|(sec, _)| sec),
+            ended_at.map(|(_, nano)| nano),
+            info.hostname,
+            info.label,
+            info.processor,
+            info.artifact
+        ])?;
+
+        let mut stmt = self.db.prepare_cached(
+            r#"
+        INSERT INTO fs_walker_session_roots (session_id, position, path, mount_path) VALUES(?1, ?2, ?3, ?4)
+        "#,
+        )?;
+        for (position, (path, mount_path)) in info.roots.iter().enumerate() {
+            stmt.execute(params![
+                self.session_id,
+                position as u32,
+                path_to_bytes(path),
+                path_to_bytes(mount_path)
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn end_session(self) -> Result<Self::Cache> {
+        if self.dry_run {
+            return Ok(self.db);
+        }
+        // entries not seen by this session under its roots were removed, the other ones belong to other scopes
+        for root in &self.roots {
+            let sql_root = path_to_bytes(root);
+            let (children_low, children_high) = children_range(&sql_root);
+            let mut stmt = self.db.prepare_cached(
+                r#"
+            INSERT INTO fs_walker_cache_history (namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+                inode, device, content_hash, racy, session_id, item, replaced_session_id)
+            SELECT namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size,
+                inode, device, content_hash, racy, session_id, item, ?1
+            FROM fs_walker_cache
+            WHERE namespace = ?4 AND last_seen_session < ?1
+                AND (path = ?2 OR (path >= ?3 AND path < ?5))
+            "#,
+            )?;
+            stmt.execute(params![
+                self.session_id,
+                sql_root,
+                children_low,
+                self.namespace,
+                children_high
+            ])?;
+
+            let mut stmt = self.db.prepare_cached(
+                r#"
+            DELETE FROM fs_walker_cache
+            WHERE namespace = ?4 AND last_seen_session < ?1
+                AND (path = ?2 OR (path >= ?3 AND path < ?5))
+            "#,
+            )?;
+            stmt.execute(params![
+                self.session_id,
+                sql_root,
+                children_low,
+                self.namespace,
+                children_high
+            ])?;
+        }
         Ok(self.db)
     }
 
@@ -159,78 +482,455 @@ impl<'c, I: ToSql + FromSql> MemoizedFsCacheSession<I> for SqliteSycnSession<'c>
     }
 }
 
+fn time_to_sql(time: SystemTime) -> Result<(u64, u32)> {
+    let duration = time.duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok((duration.as_secs(), duration.subsec_nanos()))
+}
+
+fn time_from_sql(sec: u64, nano: u32) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(sec, nano)
+}
+
+/// Version of the schema written by this version of the crate, see `SCHEMA_MIGRATIONS`
+pub const SQLITE_SCHEMA_VERSION: u32 = SCHEMA_MIGRATIONS.len() as u32;
+
+/// Migrations in order, the one at index `n` upgrades a database from version `n` to `n + 1`.
+/// Existing migrations must never be changed, append a new one to evolve the schema.
+const SCHEMA_MIGRATIONS: &[Migration] = &[
+    // 1: the schema from before the versioning
+    Migration::Sql(
+        r#"
+    CREATE TABLE fs_walker_sessions (
+        session_id INTEGER NOT NULL,
+        PRIMARY KEY (session_id)
+    );
+    CREATE TABLE fs_walker_cache (
+        path TEXT NOT NULL,
+        mtime_sec INTEGER NOT NULL,
+        mtime_nano INTEGER NOT NULL,
+        session_id INTEGER NOT NULL,
+        item BLOB,
+        PRIMARY KEY (path),
+        FOREIGN KEY (session_id) REFERENCES fs_walker_sessions (session_id)
+    );
+    "#,
+    ),
+    // 2: change detection columns, namespaces, session metadata, history and paths as bytes
+    Migration::Sql(
+        r#"
+    ALTER TABLE fs_walker_sessions ADD COLUMN namespace TEXT NOT NULL DEFAULT '';
+    ALTER TABLE fs_walker_sessions ADD COLUMN started_sec INTEGER;
+    ALTER TABLE fs_walker_sessions ADD COLUMN started_nano INTEGER;
+    ALTER TABLE fs_walker_sessions ADD COLUMN ended_sec INTEGER;
+    ALTER TABLE fs_walker_sessions ADD COLUMN ended_nano INTEGER;
+    ALTER TABLE fs_walker_sessions ADD COLUMN hostname TEXT;
+    ALTER TABLE fs_walker_sessions ADD COLUMN label TEXT;
+    ALTER TABLE fs_walker_sessions ADD COLUMN processor TEXT;
+    ALTER TABLE fs_walker_sessions ADD COLUMN artifact TEXT;
+    CREATE TABLE fs_walker_session_roots (
+        session_id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        path BLOB NOT NULL,
+        mount_path BLOB NOT NULL,
+        PRIMARY KEY (session_id, position),
+        FOREIGN KEY (session_id) REFERENCES fs_walker_sessions (session_id)
+    );
+    CREATE TABLE fs_walker_cache_v2 (
+        namespace TEXT NOT NULL DEFAULT '',
+        path BLOB NOT NULL,
+        mtime_sec INTEGER NOT NULL,
+        mtime_nano INTEGER NOT NULL,
+        ctime_sec INTEGER NOT NULL DEFAULT 0,
+        ctime_nano INTEGER NOT NULL DEFAULT 0,
+        size INTEGER NOT NULL DEFAULT 0,
+        inode INTEGER NOT NULL DEFAULT 0,
+        content_hash BLOB,
+        racy INTEGER NOT NULL DEFAULT 0,
+        session_id INTEGER NOT NULL,
+        last_seen_session INTEGER NOT NULL DEFAULT 0,
+        item BLOB,
+        PRIMARY KEY (namespace, path),
+        FOREIGN KEY (session_id) REFERENCES fs_walker_sessions (session_id)
+    );
+    -- paths used to be stored as lossy utf-8 text
+    INSERT INTO fs_walker_cache_v2 (path, mtime_sec, mtime_nano, session_id, last_seen_session, item)
+    SELECT CAST(path AS BLOB), mtime_sec, mtime_nano, session_id, session_id, item FROM fs_walker_cache;
+    DROP TABLE fs_walker_cache;
+    ALTER TABLE fs_walker_cache_v2 RENAME TO fs_walker_cache;
+    CREATE INDEX fs_walker_cache_last_seen ON fs_walker_cache (namespace, last_seen_session);
+    -- previous states of the cache rows, replaced or removed by the session replaced_session_id
+    CREATE TABLE fs_walker_cache_history (
+        namespace TEXT NOT NULL,
+        path BLOB NOT NULL,
+        mtime_sec INTEGER NOT NULL,
+        mtime_nano INTEGER NOT NULL,
+        ctime_sec INTEGER NOT NULL,
+        ctime_nano INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        inode INTEGER NOT NULL,
+        content_hash BLOB,
+        racy INTEGER NOT NULL,
+        session_id INTEGER NOT NULL,
+        item BLOB,
+        replaced_session_id INTEGER NOT NULL
+    );
+    CREATE INDEX fs_walker_cache_history_replaced ON fs_walker_cache_history (replaced_session_id);
+    "#,
+    ),
+    // 3: settings of the database
+    Migration::Sql(
+        r#"
+    CREATE TABLE fs_walker_settings (
+        name TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (name)
+    );
+    -- items used to be encoded with bincode
+    INSERT INTO fs_walker_settings (name, value) VALUES ('item_codec', 'bincode');
+    "#,
+    ),
+    // 4: device of the entries, to find moved entries by inode
+    Migration::Sql(
+        r#"
+    ALTER TABLE fs_walker_cache ADD COLUMN device INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE fs_walker_cache_history ADD COLUMN device INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX fs_walker_cache_inode ON fs_walker_cache (namespace, inode, device);
+    "#,
+    ),
+    // 5: the items of `FsNode` gained the metadata of the entries and lost the children of the folders
+    Migration::Rust(upgrade_fs_node_items),
+];
+
+/// Step of `SCHEMA_MIGRATIONS`
+enum Migration {
+    Sql(&'static str),
+    /// for the changes sql can not do, like rewriting the items
+    Rust(fn(&Connection) -> Result<()>),
+}
+
+/// Rewrite the `FsNode` items of the previous layout, their metadata being unknown.
+/// Only the items of the sessions of the change notifier, the tar processor or of unknown processors are read,
+/// the ones which are not encoded exactly as an item of the previous layout are left untouched
+fn upgrade_fs_node_items(db: &Connection) -> Result<()> {
+    for table in ["fs_walker_cache", "fs_walker_cache_history"] {
+        let rows: Vec<(i64, Vec<u8>)> = db
+            .prepare(&format!(
+                r#"
+            SELECT c.rowid, c.item FROM {} c JOIN fs_walker_sessions s ON s.session_id = c.session_id
+            WHERE c.item IS NOT NULL AND (s.processor IS NULL OR s.processor IN ('change_notifier', 'tar'))
+            "#,
+                table
+            ))?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        if rows.is_empty() {
+            continue;
+        }
+        let codec = sqlite_item_codec(db)?;
+        let mut stmt = db.prepare(&format!("UPDATE {} SET item = ?2 WHERE rowid = ?1", table))?;
+        for (row_id, item) in rows {
+            if let Some(item) = fs_node_v4::upgrade(codec, &item)? {
+                stmt.execute(params![row_id, item])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Frozen copies of the `FsNode` layouts before and after the migration 5, later changes of `FsNode` must not alter them
+mod fs_node_v4 {
+    use anyhow::Result;
+    use serde::{Deserialize, Serialize};
+
+    use crate::ItemCodec;
+
+    #[derive(Clone, Copy, Serialize, Deserialize)]
+    enum SpecialKind {
+        Fifo,
+        Socket,
+        BlockDevice,
+        CharDevice,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum FsNodeType {
+        File,
+        Symlink,
+        Folder,
+        Special(SpecialKind),
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum FsNode {
+        File,
+        Symlink,
+        /// children names as bytes, with their types
+        Folder(Vec<(Vec<u8>, FsNodeType)>),
+        Special(SpecialKind),
+    }
+
+    /// `None` metadata, encoded the same whatever the type of the option
+    type NoMeta = Option<()>;
+
+    #[derive(Serialize)]
+    enum FsNodeV5 {
+        File(NoMeta),
+        Symlink(NoMeta),
+        Folder(NoMeta),
+        Special(SpecialKind, NoMeta),
+    }
+
+    /// the item of the new layout, `None` if `item` is not an item of the previous layout
+    pub(super) fn upgrade(codec: ItemCodec, item: &[u8]) -> Result<Option<Vec<u8>>> {
+        let node = match codec.decode::<FsNode>(item) {
+            // the items of other processors may be decoded from a prefix of their bytes
+            Ok(node) if codec.encode(&node)? == item => node,
+            _ => return Ok(None),
+        };
+        let upgraded = match node {
+            FsNode::File => FsNodeV5::File(None),
+            FsNode::Symlink => FsNodeV5::Symlink(None),
+            FsNode::Folder(_) => FsNodeV5::Folder(None),
+            FsNode::Special(kind) => FsNodeV5::Special(kind, None),
+        };
+        Ok(Some(codec.encode(&upgraded)?))
+    }
+}
+
+/// Create the cache tables or upgrade them to `SQLITE_SCHEMA_VERSION`, to be called each time
+/// the database is opened. Fails without touching the database if it was written by a newer version.
 pub fn setup_sqlite_cache(db: &Connection) -> Result<()> {
+    // a savepoint works inside or outside of a transaction
+    db.execute_batch("SAVEPOINT fs_walker_setup")?;
+    match migrate_schema(db) {
+        Ok(()) => db.execute_batch("RELEASE fs_walker_setup")?,
+        Err(err) => {
+            db.execute_batch("ROLLBACK TO fs_walker_setup; RELEASE fs_walker_setup")?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn migrate_schema(db: &Connection) -> Result<()> {
+    let version = sqlite_schema_version(db)?;
+    if version > SQLITE_SCHEMA_VERSION {
+        bail!(
+            "the cache schema version {} is newer than the version {} supported by this build, \
+            it was written by a more recent version of sausage",
+            version,
+            SQLITE_SCHEMA_VERSION
+        );
+    }
+    for migration in &SCHEMA_MIGRATIONS[version as usize..] {
+        match migration {
+            Migration::Sql(sql) => db.execute_batch(sql)?,
+            Migration::Rust(migrate) => migrate(db)?,
+        }
+    }
+    if version == 0 {
+        set_setting(db, "item_codec", ItemCodec::default().name())?;
+    }
     db.execute_batch(
         r#"
-        CREATE TABLE IF NOT EXISTS fs_walker_sessions (
-            session_id INTEGER NOT NULL,
-            PRIMARY KEY (session_id)
-        );
-        CREATE TABLE IF NOT EXISTS fs_walker_cache (
-            path TEXT NOT NULL, 
-            mtime_sec INTEGER NOT NULL,
-            mtime_nano INTEGER NOT NULL,
-            session_id INTEGER NOT NULL,
-            item BLOB,
-            PRIMARY KEY (path),
-            FOREIGN KEY (session_id) REFERENCES fs_walker_sessions (session_id) 
+        CREATE TABLE IF NOT EXISTS fs_walker_schema_version (version INTEGER NOT NULL);
+        DELETE FROM fs_walker_schema_version;
+        "#,
+    )?;
+    db.execute(
+        "INSERT INTO fs_walker_schema_version (version) VALUES (?1)",
+        params![SQLITE_SCHEMA_VERSION],
+    )?;
+    Ok(())
+}
+
+/// Version of the cache schema in `db`, 0 for an empty database
+pub fn sqlite_schema_version(db: &Connection) -> Result<u32> {
+    let table_exists = |name: &str| -> Result<bool> {
+        let mut stmt = db.prepare_cached(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        )?;
+        Ok(stmt.query_row(params![name], |row| row.get(0))?)
+    };
+    if table_exists("fs_walker_schema_version")? {
+        let version = db.query_row(
+            "SELECT MAX(version) FROM fs_walker_schema_version",
+            params![],
+            |row| row.get::<_, Option<u32>>(0),
+        )?;
+        return Ok(version.unwrap_or(0));
+    }
+    // databases created before the versioning have the first schema
+    Ok(if table_exists("fs_walker_cache")? {
+        1
+    } else {
+        0
+    })
+}
+
+fn check_schema_version(db: &Connection) -> Result<()> {
+    let version = sqlite_schema_version(db)?;
+    if version != SQLITE_SCHEMA_VERSION {
+        bail!(
+            "the cache schema version is {} instead of {}, setup_sqlite_cache must be called first",
+            version,
+            SQLITE_SCHEMA_VERSION
         );
+    }
+    Ok(())
+}
+
+fn setting(db: &Connection, name: &str) -> Result<String> {
+    let mut stmt = db.prepare_cached(
+        r#"
+    SELECT value FROM fs_walker_settings WHERE name = ?1
     "#,
     )?;
+    Ok(stmt.query_row(params![name], |row| row.get(0))?)
+}
+
+fn set_setting(db: &Connection, name: &str, value: &str) -> Result<()> {
+    let mut stmt = db.prepare_cached(
+        r#"
+    INSERT OR REPLACE INTO fs_walker_settings (name, value) VALUES (?1, ?2)
+    "#,
+    )?;
+    stmt.execute(params![name, value])?;
     Ok(())
 }
 
-pub fn rollback_before_session_id(db: &Connection, id: u32) -> Result<()> {
+/// Encoding of the items saved in `db`, `ItemCodec::default()` for new databases
+pub fn sqlite_item_codec(db: &Connection) -> Result<ItemCodec> {
+    ItemCodec::from_name(&setting(db, "item_codec")?)
+}
+
+/// Choose the encoding of the items saved in `db`, only possible while no item is saved
+pub fn set_sqlite_item_codec(db: &Connection, codec: ItemCodec) -> Result<()> {
+    let current = setting(db, "item_codec")?;
+    if current == codec.name() {
+        return Ok(());
+    }
+    let has_items: bool = db.query_row(
+        r#"
+    SELECT EXISTS (SELECT 1 FROM fs_walker_cache) OR EXISTS (SELECT 1 FROM fs_walker_cache_history)
+    "#,
+        params![],
+        |row| row.get(0),
+    )?;
+    if has_items {
+        bail!(
+            "the items of the cache are encoded with {}, the codec can only be changed while the cache is empty",
+            current
+        );
+    }
+    set_setting(db, "item_codec", codec.name())
+}
+
+/// Restore the cache of `namespace` as it was at the end of its last session before `id`,
+/// forgetting the sessions of this namespace from `id`
+pub fn rollback_before_session_id(db: &Connection, namespace: &str, id: u32) -> Result<()> {
     let mut stmt = db.prepare(
         r#"
-    DELETE FROM fs_walker_cache WHERE session_id >= ?
+    DELETE FROM fs_walker_cache WHERE namespace = ?2 AND session_id >= ?1
     "#,
     )?;
-    stmt.execute(params![id])?;
+    stmt.execute(params![id, namespace])?;
 
+    // rows written before `id` and replaced or removed since
     let mut stmt = db.prepare(
         r#"
-    DELETE FROM fs_walker_sessions WHERE session_id >= ?
+    INSERT INTO fs_walker_cache (namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size, inode,
+        device, content_hash, racy, session_id, last_seen_session, item)
+    SELECT namespace, path, mtime_sec, mtime_nano, ctime_sec, ctime_nano, size, inode,
+        device, content_hash, racy, session_id, ?1 - 1, item
+    FROM fs_walker_cache_history WHERE namespace = ?2 AND session_id < ?1 AND replaced_session_id >= ?1
     "#,
     )?;
-    stmt.execute(params![id])?;
+    stmt.execute(params![id, namespace])?;
+
+    // every remaining entry was part of the cache at the end of the session `id - 1`
+    let mut stmt = db.prepare(
+        r#"
+    UPDATE fs_walker_cache SET last_seen_session = ?1 - 1 WHERE namespace = ?2 AND last_seen_session >= ?1
+    "#,
+    )?;
+    stmt.execute(params![id, namespace])?;
+
+    let mut stmt = db.prepare(
+        r#"
+    DELETE FROM fs_walker_cache_history WHERE namespace = ?2 AND replaced_session_id >= ?1
+    "#,
+    )?;
+    stmt.execute(params![id, namespace])?;
+
+    let mut stmt = db.prepare(
+        r#"
+    DELETE FROM fs_walker_session_roots WHERE session_id IN (
+        SELECT session_id FROM fs_walker_sessions WHERE namespace = ?2 AND session_id >= ?1)
+    "#,
+    )?;
+    stmt.execute(params![id, namespace])?;
+
+    let mut stmt = db.prepare(
+        r#"
+    DELETE FROM fs_walker_sessions WHERE namespace = ?2 AND session_id >= ?1
+    "#,
+    )?;
+    stmt.execute(params![id, namespace])?;
     Ok(())
 }
 
-impl ToSql for crate::change_watcher::FsNode {
-    fn to_sql(&self) -> std::result::Result<ToSqlOutput<'_>, rusqlite::Error> {
-        #[cfg(feature = "sqlite_debug")]
-        {
-            let binary = serde_json::to_string_pretty(self)
-                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
-            Ok(ToSqlOutput::Owned(Value::Blob(binary.into_bytes())))
-        }
-        #[cfg(not(feature = "sqlite_debug"))]
-        {
-            let binary =
-                bincode::serialize(self).map_err(|e| rusqlite::Error::ToSqlConversionFailure(e))?;
-            Ok(ToSqlOutput::Owned(Value::Blob(binary)))
-        }
+/// Sessions saved in the cache, from the oldest to the latest
+pub fn list_sessions(db: &Connection) -> Result<Vec<SessionInfo>> {
+    let mut stmt = db.prepare(
+        r#"
+    SELECT session_id, started_sec, started_nano, ended_sec, ended_nano, hostname, label, processor, artifact,
+        namespace
+    FROM fs_walker_sessions ORDER BY session_id
+    "#,
+    )?;
+    let mut sessions = stmt
+        .query_map(params![], |row| {
+            let time_at = |sec: Option<u64>, nano: Option<u32>| {
+                sec.map(|sec| time_from_sql(sec, nano.unwrap_or(0)))
+            };
+            Ok(SessionInfo {
+                session_id: row.get(0)?,
+                namespace: row.get(9)?,
+                started_at: time_at(row.get(1)?, row.get(2)?).unwrap_or(SystemTime::UNIX_EPOCH),
+                ended_at: time_at(row.get(3)?, row.get(4)?),
+                hostname: row.get(5)?,
+                roots: Vec::new(),
+                label: row.get(6)?,
+                processor: row.get(7)?,
+                artifact: row.get(8)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt = db.prepare_cached(
+        r#"
+    SELECT path, mount_path FROM fs_walker_session_roots WHERE session_id = ?1 ORDER BY position
+    "#,
+    )?;
+    for session in &mut sessions {
+        session.roots = stmt
+            .query_map(params![session.session_id], |row| {
+                Ok((path_from_bytes(row.get(0)?), path_from_bytes(row.get(1)?)))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
     }
+    Ok(sessions)
 }
 
-impl FromSql for crate::change_watcher::FsNode {
-    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
-        match value {
-            ValueRef::Blob(bytes) => {
-                #[cfg(feature = "sqlite_debug")]
-                {
-                    let node = serde_json::from_slice(bytes)
-                        .map_err(|e| FromSqlError::Other(Box::new(e)))?;
-                    Ok(node)
-                }
-                #[cfg(not(feature = "sqlite_debug"))]
-                {
-                    let node = bincode::deserialize(bytes).map_err(|e| FromSqlError::Other(e))?;
-                    Ok(node)
-                }
-            }
-            _ => Err(FromSqlError::InvalidType),
-        }
-    }
+/// Forget the history needed to rollback before the session `id`, `rollback_before_session_id`
+/// can then only restore the session `id - 1` or a later one
+pub fn prune_history_before_session_id(db: &Connection, id: u32) -> Result<()> {
+    let mut stmt = db.prepare(
+        r#"
+    DELETE FROM fs_walker_cache_history WHERE replaced_session_id < ?
+    "#,
+    )?;
+    stmt.execute(params![id])?;
+    Ok(())
 }
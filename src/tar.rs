@@ -1,117 +1,193 @@
 use std::{
     io::Write,
     path::{Path, PathBuf},
+    sync::Arc,
     time::SystemTime,
 };
 
-use crate::{change_watcher::FsNode, ChangeNotifier, FsChangeWatcher, FsEntry, FsProcessor};
+use crate::{
+    change_watcher::{FsNode, FsNodeType},
+    path_bytes::path_to_bytes,
+    vfs::{StdFs, Vfs, VfsMetadata},
+    ChangeAction, ChangeEvent, ChangeNotifier, ChangeSink, FsEntry, FsProcessor, SessionReport,
+    SpecialKind,
+};
 
 use anyhow::Result;
 use std::collections::HashMap;
-use tar_impl::{Builder, Header, HeaderMode};
+use tar_impl::{Builder, EntryType, Header};
 
 pub struct TarProcessor<W: Write>(ChangeNotifier<TarNotifier<W>>);
 
 struct TarNotifier<W: Write> {
     builder: Builder<W>,
+    vfs: Arc<dyn Vfs>,
+    /// size of the archived files
+    bytes_read: u64,
 }
 
 impl<W: Write> TarProcessor<W> {
     pub fn new(writer: W) -> Self {
-        let mut builder = Builder::new(writer);
-        builder.follow_symlinks(false);
-        builder.mode(HeaderMode::Complete);
-        let notifier = ChangeNotifier::new(TarNotifier { builder });
-        Self(notifier)
+        Self::with_vfs(writer, Arc::new(StdFs))
     }
-}
 
-impl<W: Write> FsChangeWatcher for TarNotifier<W> {
-    fn notify_file_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_path_with_name(path, mount_path)?;
-        Ok(())
+    /// Archive the entries read from `vfs`, which has to be the one walked by the session
+    pub fn with_vfs(writer: W, vfs: Arc<dyn Vfs>) -> Self {
+        let tar_notifier = TarNotifier {
+            builder: Builder::new(writer),
+            vfs: vfs.clone(),
+            bytes_read: 0,
+        };
+        let notifier = ChangeNotifier::with_vfs(tar_notifier, vfs);
+        Self(notifier)
     }
+}
 
-    fn notify_file_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_path_with_name(path, mount_path)?;
-        Ok(())
-    }
+/// seconds since the unix epoch, 0 for older times
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
 
-    fn notify_file_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+impl<W: Write> TarNotifier<W> {
+    /// header holding the metadata of `path`, with an empty size
+    fn header(&self, path: &Path, entry_type: EntryType) -> Result<(Header, VfsMetadata)> {
+        let meta = self.vfs.symlink_metadata(path)?;
         let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(meta.mode);
+        header.set_uid(meta.uid);
+        header.set_gid(meta.gid);
+        header.set_mtime(unix_time(meta.mtime));
         header.set_size(0);
-        header.set_mtime(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs(),
-        );
-        let data: &[u8] = &[];
-        let new_filename = format!(
-            "{}.DELETED",
-            mount_path.file_name().unwrap().to_string_lossy()
-        );
-        self.builder
-            .append_data(&mut header, mount_path.with_file_name(new_filename), data)?;
+        Ok((header, meta))
+    }
+
+    fn append_file(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        let (mut header, meta) = self.header(path, EntryType::Regular)?;
+        header.set_size(meta.size);
+        let data = self.vfs.open(path)?;
+        self.builder.append_data(&mut header, mount_path, data)?;
+        self.bytes_read += meta.size;
         Ok(())
     }
 
-    fn notify_symlink_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_path_with_name(path, mount_path)?;
+    fn append_symlink(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        let (mut header, _) = self.header(path, EntryType::Symlink)?;
+        let target = self.vfs.read_link(path)?;
+        self.builder.append_link(&mut header, mount_path, target)?;
         Ok(())
     }
 
-    fn notify_symlink_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_path_with_name(path, mount_path)?;
+    fn append_folder(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        let (mut header, _) = self.header(path, EntryType::Directory)?;
+        self.builder
+            .append_data(&mut header, mount_path, std::io::empty())?;
         Ok(())
     }
 
-    fn notify_symlink_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+    /// `<name>.<suffix>` file next to the entry of `mount_path`
+    fn append_marker(&mut self, mount_path: &Path, suffix: &str, data: &[u8]) -> Result<()> {
         let mut header = Header::new_gnu();
-        header.set_size(0);
-        header.set_mtime(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs(),
-        );
-        let data: &[u8] = &[];
-        let new_filename = format!(
-            "{}.DELETED",
-            mount_path.file_name().unwrap().to_string_lossy()
-        );
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(unix_time(self.vfs.now()));
         self.builder
-            .append_data(&mut header, mount_path.with_file_name(new_filename), data)?;
+            .append_data(&mut header, marker_path(mount_path, suffix), data)?;
         Ok(())
     }
 
-    fn notify_folder_added(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_dir(mount_path, path)?;
+    /// mark a file whose content did not change with an empty `<name>.METADATA` file,
+    /// its header holding the new mode, owner and mtime of the file
+    fn append_metadata(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
+        let (mut header, _) = self.header(path, EntryType::Regular)?;
+        self.builder.append_data(
+            &mut header,
+            marker_path(mount_path, "METADATA"),
+            std::io::empty(),
+        )?;
         Ok(())
     }
 
-    fn notify_folder_changed(&mut self, path: &Path, mount_path: &Path) -> Result<()> {
-        self.builder.append_dir(mount_path, path)?;
-        Ok(())
+    /// mark an entry as deleted with an empty `<name>.DELETED` file
+    fn append_deleted(&mut self, mount_path: &Path) -> Result<()> {
+        self.append_marker(mount_path, "DELETED", &[])
     }
 
-    fn notify_folder_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
-        let mut header = Header::new_gnu();
-        header.set_size(0);
-        header.set_mtime(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_secs(),
-        );
-        let data: &[u8] = &[];
-        let new_filename = format!(
-            "{}.DELETED",
-            mount_path.file_name().unwrap().to_string_lossy()
-        );
+    /// mark an entry as moved with a `<name>.MOVED` file holding its new mount path,
+    /// the content of a moved folder is not archived again
+    fn append_moved(&mut self, from_mount_path: &Path, mount_path: &Path) -> Result<()> {
+        self.append_marker(from_mount_path, "MOVED", &path_to_bytes(mount_path))
+    }
+
+    /// fifo and device entries only have a header, sockets can not be archived and are skipped
+    fn append_special(&mut self, path: &Path, mount_path: &Path, kind: SpecialKind) -> Result<()> {
+        let entry_type = match kind {
+            SpecialKind::Fifo => EntryType::Fifo,
+            SpecialKind::BlockDevice => EntryType::Block,
+            SpecialKind::CharDevice => EntryType::Char,
+            SpecialKind::Socket => return Ok(()),
+        };
+        let (mut header, meta) = self.header(path, entry_type)?;
+        if kind != SpecialKind::Fifo {
+            let (major, minor) = device_numbers(meta.rdev);
+            header.set_device_major(major)?;
+            header.set_device_minor(minor)?;
+        }
         self.builder
-            .append_data(&mut header, mount_path.with_file_name(new_filename), data)?;
+            .append_data(&mut header, mount_path, std::io::empty())?;
         Ok(())
     }
 }
 
+/// `<name>.<suffix>` next to `mount_path`
+fn marker_path(mount_path: &Path, suffix: &str) -> PathBuf {
+    let file_name = format!(
+        "{}.{}",
+        mount_path.file_name().unwrap().to_string_lossy(),
+        suffix
+    );
+    mount_path.with_file_name(file_name)
+}
+
+/// split a device id into its major and minor numbers, using the glibc encoding
+fn device_numbers(rdev: u64) -> (u32, u32) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+impl<W: Write> ChangeSink for TarNotifier<W> {
+    /// files with only a metadata change get a header-only marker, the other entries have no data
+    /// and are archived again
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        let ChangeEvent {
+            kind,
+            action,
+            path,
+            mount_path,
+            ..
+        } = event;
+        match (action, kind) {
+            (_, FsNodeType::Special(SpecialKind::Socket)) => Ok(()),
+            (ChangeAction::Removed, _) => self.append_deleted(&mount_path),
+            (
+                ChangeAction::Moved {
+                    from_mount_path, ..
+                },
+                _,
+            ) => self.append_moved(&from_mount_path, &mount_path),
+            (ChangeAction::MetadataChanged, FsNodeType::File) => {
+                self.append_metadata(&path, &mount_path)
+            }
+            (_, FsNodeType::File) => self.append_file(&path, &mount_path),
+            (_, FsNodeType::Symlink) => self.append_symlink(&path, &mount_path),
+            (_, FsNodeType::Folder) => self.append_folder(&path, &mount_path),
+            (_, FsNodeType::Special(kind)) => self.append_special(&path, &mount_path, kind),
+        }
+    }
+}
+
 impl<W: Write> FsProcessor for TarProcessor<W> {
     type Item = FsNode;
 
@@ -133,6 +209,16 @@ impl<W: Write> FsProcessor for TarProcessor<W> {
         self.0.process_symlink(path, mount_path, previous)
     }
 
+    fn process_special(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        self.0.process_special(path, mount_path, kind, previous)
+    }
+
     fn process_folder(
         &mut self,
         path: &Path,
@@ -142,4 +228,33 @@ impl<W: Write> FsProcessor for TarProcessor<W> {
     ) -> Result<Self::Item> {
         self.0.process_folder(path, mount_path, sub, previous)
     }
+
+    fn process_removed(&mut self, path: &Path, mount_path: &Path, item: Self::Item) -> Result<()> {
+        self.0.process_removed(path, mount_path, item)
+    }
+
+    fn process_moved(
+        &mut self,
+        from: &Path,
+        from_mount_path: &Path,
+        path: &Path,
+        mount_path: &Path,
+        item: &Self::Item,
+    ) -> Result<()> {
+        self.0
+            .process_moved(from, from_mount_path, path, mount_path, item)
+    }
+
+    fn finish_processing(&mut self) -> Result<()> {
+        self.0.finish_processing()
+    }
+
+    fn fill_report(&self, report: &mut SessionReport) {
+        self.0.fill_report(report);
+        report.bytes_read += self.0.sink().bytes_read;
+    }
+
+    fn kind(&self) -> &'static str {
+        "tar"
+    }
 }
@@ -0,0 +1,152 @@
+use std::{
+    ffi::OsString,
+    fs::{FileType, Metadata},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::SpecialKind;
+
+mod memory;
+pub use memory::{MemoryFs, VfsOp};
+
+/// File system accessed by the walker, the change detector, the exclude rules and `TarProcessor`.
+/// `StdFs` is the real one, `MemoryFs` a virtual one to test sessions deterministically
+pub trait Vfs: Send + Sync {
+    /// metadata of `path` itself, symlinks are not followed
+    fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata>;
+
+    /// entries of the folder `path`, in no particular order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsDirEntry>>;
+
+    /// target of the symlink `path`
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// open the file `path` for reading, symlinks are followed
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// current time of this file system, used as the start of the sessions and for racy entries
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VfsFileType {
+    File,
+    Folder,
+    Symlink,
+    Special(SpecialKind),
+}
+
+impl VfsFileType {
+    /// unknown kinds of entries are handled as symlinks
+    pub fn from_file_type(ft: &FileType) -> Self {
+        if ft.is_file() {
+            VfsFileType::File
+        } else if ft.is_dir() {
+            VfsFileType::Folder
+        } else if ft.is_symlink() {
+            VfsFileType::Symlink
+        } else if let Some(kind) = SpecialKind::from_file_type(ft) {
+            VfsFileType::Special(kind)
+        } else {
+            VfsFileType::Symlink
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        *self == VfsFileType::Folder
+    }
+}
+
+/// Metadata of a file system entry, the unix only fields are 0 on other platforms
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfsMetadata {
+    pub file_type: VfsFileType,
+    pub mtime: SystemTime,
+    /// time of the last status change
+    pub ctime: SystemTime,
+    pub size: u64,
+    pub inode: u64,
+    /// device holding the entry
+    pub device: u64,
+    /// permission bits, without the file type
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// device id of block and char devices
+    pub rdev: u64,
+}
+
+impl VfsMetadata {
+    pub fn from_metadata(meta: &Metadata) -> io::Result<Self> {
+        let mtime = meta.modified()?;
+        #[cfg(unix)]
+        let (ctime, inode, device, mode, uid, gid, rdev) = {
+            use std::{os::unix::fs::MetadataExt, time::Duration};
+            let ctime = SystemTime::UNIX_EPOCH
+                + Duration::new(meta.ctime() as u64, meta.ctime_nsec() as u32);
+            (
+                ctime,
+                meta.ino(),
+                meta.dev(),
+                meta.mode() & 0o7777,
+                meta.uid() as u64,
+                meta.gid() as u64,
+                meta.rdev(),
+            )
+        };
+        #[cfg(not(unix))]
+        let (ctime, inode, device, mode, uid, gid, rdev) = (mtime, 0, 0, 0, 0, 0, 0);
+        Ok(Self {
+            file_type: VfsFileType::from_file_type(&meta.file_type()),
+            mtime,
+            ctime,
+            size: meta.len(),
+            inode,
+            device,
+            mode,
+            uid,
+            gid,
+            rdev,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VfsDirEntry {
+    pub name: OsString,
+    pub file_type: VfsFileType,
+}
+
+/// The file system of the operating system, used by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFs;
+
+impl Vfs for StdFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+        VfsMetadata::from_metadata(&path.symlink_metadata()?)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsDirEntry>> {
+        std::fs::read_dir(path)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(VfsDirEntry {
+                    name: entry.file_name(),
+                    file_type: VfsFileType::from_file_type(&entry.file_type()?),
+                })
+            })
+            .collect()
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+}
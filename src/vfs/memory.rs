@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{self, Cursor, ErrorKind, Read},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use super::{Vfs, VfsDirEntry, VfsFileType, VfsMetadata};
+use crate::SpecialKind;
+
+/// Operation of a `Vfs`, see `MemoryFs::fail`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VfsOp {
+    Metadata,
+    ReadDir,
+    ReadLink,
+    Open,
+}
+
+/// File system kept in memory with its own clock, which only moves with `advance`.
+/// Every change sets the mtime and ctime of the entry and of its parent folder to the current time,
+/// like a real file system. Paths are absolute, the root folder `/` always exists.
+/// Symlinks are only followed by `open`, for the last component of the path
+pub struct MemoryFs {
+    state: Mutex<MemoryFsState>,
+}
+
+struct MemoryFsState {
+    // ordered by components, the entries below a path directly follow it
+    nodes: BTreeMap<PathBuf, MemoryNode>,
+    now: SystemTime,
+    last_inode: u64,
+    faults: HashSet<(PathBuf, VfsOp)>,
+}
+
+struct MemoryNode {
+    meta: VfsMetadata,
+    content: MemoryContent,
+}
+
+enum MemoryContent {
+    Empty,
+    File(Arc<[u8]>),
+    Symlink(PathBuf),
+}
+
+/// device id of the entries of a `MemoryFs`
+const MEMORY_DEVICE: u64 = 1;
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        let mut state = MemoryFsState {
+            nodes: BTreeMap::new(),
+            now: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            last_inode: 0,
+            faults: HashSet::new(),
+        };
+        let root = state.new_node(VfsFileType::Folder, 0o755, MemoryContent::Empty);
+        state.nodes.insert(PathBuf::from("/"), root);
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryFsState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Move the clock forward, entries changed from now on get a different mtime
+    pub fn advance(&self, duration: Duration) {
+        self.state().now += duration;
+    }
+
+    /// Create a folder and its missing parents
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut state = self.state();
+        let mut ancestors: Vec<&Path> = path.as_ref().ancestors().collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            match state.nodes.get(ancestor) {
+                Some(node) if node.meta.file_type.is_dir() => {}
+                Some(_) => return Err(already_exists(ancestor)),
+                None => {
+                    let node = state.new_node(VfsFileType::Folder, 0o755, MemoryContent::Empty);
+                    state.insert(ancestor, node)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a file or replace the content of an existing one, keeping its inode
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let (path, contents) = (path.as_ref(), contents.as_ref());
+        let mut state = self.state();
+        let now = state.now;
+        match state.nodes.get_mut(path) {
+            Some(node) if node.meta.file_type == VfsFileType::File => {
+                node.content = MemoryContent::File(contents.into());
+                node.meta.size = contents.len() as u64;
+                node.meta.mtime = now;
+                node.meta.ctime = now;
+                Ok(())
+            }
+            Some(_) => Err(already_exists(path)),
+            None => {
+                let mut node = state.new_node(
+                    VfsFileType::File,
+                    0o644,
+                    MemoryContent::File(contents.into()),
+                );
+                node.meta.size = contents.len() as u64;
+                state.insert(path, node)
+            }
+        }
+    }
+
+    /// Create a symlink at `link` pointing to `target`
+    pub fn symlink(&self, target: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+        let (target, link) = (target.as_ref(), link.as_ref());
+        let mut state = self.state();
+        let mut node = state.new_node(
+            VfsFileType::Symlink,
+            0o777,
+            MemoryContent::Symlink(target.to_path_buf()),
+        );
+        node.meta.size = target.as_os_str().len() as u64;
+        state.insert(link, node)
+    }
+
+    /// Create a fifo, a socket or a device, `rdev` being the id of the device
+    pub fn mknod(&self, path: impl AsRef<Path>, kind: SpecialKind, rdev: u64) -> io::Result<()> {
+        let mut state = self.state();
+        let mut node = state.new_node(VfsFileType::Special(kind), 0o644, MemoryContent::Empty);
+        node.meta.rdev = rdev;
+        state.insert(path.as_ref(), node)
+    }
+
+    /// Remove an entry and everything below it
+    pub fn remove(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut state = self.state();
+        let removed = state.subtree(path);
+        if removed.is_empty() || path.parent().is_none() {
+            return Err(not_found(path));
+        }
+        for removed_path in removed {
+            state.nodes.remove(&removed_path);
+        }
+        state.touch_parent(path);
+        Ok(())
+    }
+
+    /// Move an entry and everything below it, keeping their inodes
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut state = self.state();
+        let moved = state.subtree(from);
+        if moved.is_empty() || from.parent().is_none() {
+            return Err(not_found(from));
+        }
+        if to.starts_with(from) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("can not move {} into itself", from.display()),
+            ));
+        }
+        if state.nodes.contains_key(to) {
+            return Err(already_exists(to));
+        }
+        state.check_parent(to)?;
+        for moved_path in moved {
+            let node = state.nodes.remove(&moved_path).unwrap();
+            let new_path = match moved_path.strip_prefix(from).unwrap() {
+                relative if relative.as_os_str().is_empty() => to.to_path_buf(),
+                relative => to.join(relative),
+            };
+            state.nodes.insert(new_path, node);
+        }
+        let now = state.now;
+        state.nodes.get_mut(to).unwrap().meta.ctime = now;
+        state.touch_parent(from);
+        state.touch_parent(to);
+        Ok(())
+    }
+
+    /// Set the modification time of an entry, like `touch -d`
+    pub fn set_mtime(&self, path: impl AsRef<Path>, mtime: SystemTime) -> io::Result<()> {
+        self.update_meta(path.as_ref(), |meta| meta.mtime = mtime)
+    }
+
+    /// Set the permission bits of an entry, like `chmod`
+    pub fn set_mode(&self, path: impl AsRef<Path>, mode: u32) -> io::Result<()> {
+        self.update_meta(path.as_ref(), |meta| meta.mode = mode & 0o7777)
+    }
+
+    /// change the metadata of an entry, which also changes its ctime
+    fn update_meta(&self, path: &Path, update: impl FnOnce(&mut VfsMetadata)) -> io::Result<()> {
+        let mut state = self.state();
+        let now = state.now;
+        let node = state.nodes.get_mut(path).ok_or_else(|| not_found(path))?;
+        update(&mut node.meta);
+        node.meta.ctime = now;
+        Ok(())
+    }
+
+    /// Make every `op` on `path` fail until `clear_faults` is called
+    pub fn fail(&self, path: impl AsRef<Path>, op: VfsOp) {
+        self.state()
+            .faults
+            .insert((path.as_ref().to_path_buf(), op));
+    }
+
+    pub fn clear_faults(&self) {
+        self.state().faults.clear();
+    }
+}
+
+impl MemoryFsState {
+    fn new_node(
+        &mut self,
+        file_type: VfsFileType,
+        mode: u32,
+        content: MemoryContent,
+    ) -> MemoryNode {
+        self.last_inode += 1;
+        MemoryNode {
+            meta: VfsMetadata {
+                file_type,
+                mtime: self.now,
+                ctime: self.now,
+                size: 0,
+                inode: self.last_inode,
+                device: MEMORY_DEVICE,
+                mode,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+            },
+            content,
+        }
+    }
+
+    /// `path` and the paths below it
+    fn subtree(&self, path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(|(node_path, _)| node_path.starts_with(path))
+            .map(|(node_path, _)| node_path.clone())
+            .collect()
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        let parent = path.parent().ok_or_else(|| already_exists(path))?;
+        match self.nodes.get(parent) {
+            Some(node) if node.meta.file_type.is_dir() => Ok(()),
+            Some(_) => Err(io::Error::other(format!(
+                "{} is not a folder",
+                parent.display()
+            ))),
+            None => Err(not_found(parent)),
+        }
+    }
+
+    /// add a new entry to an existing folder
+    fn insert(&mut self, path: &Path, node: MemoryNode) -> io::Result<()> {
+        if self.nodes.contains_key(path) {
+            return Err(already_exists(path));
+        }
+        self.check_parent(path)?;
+        self.nodes.insert(path.to_path_buf(), node);
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    /// the content of the parent folder of `path` changed
+    fn touch_parent(&mut self, path: &Path) {
+        let now = self.now;
+        if let Some(parent) = path.parent().and_then(|parent| self.nodes.get_mut(parent)) {
+            parent.meta.mtime = now;
+            parent.meta.ctime = now;
+        }
+    }
+
+    fn node(&self, path: &Path, op: VfsOp) -> io::Result<&MemoryNode> {
+        if self.faults.contains(&(path.to_path_buf(), op)) {
+            return Err(io::Error::other(format!(
+                "injected {:?} fault on {}",
+                op,
+                path.display()
+            )));
+        }
+        self.nodes.get(path).ok_or_else(|| not_found(path))
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<VfsMetadata> {
+        Ok(self.state().node(path, VfsOp::Metadata)?.meta.clone())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<VfsDirEntry>> {
+        let state = self.state();
+        if !state.node(path, VfsOp::ReadDir)?.meta.file_type.is_dir() {
+            return Err(io::Error::other(format!(
+                "{} is not a folder",
+                path.display()
+            )));
+        }
+        Ok(state
+            .nodes
+            .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+            .take_while(|(node_path, _)| node_path.starts_with(path))
+            .filter(|(node_path, _)| node_path.parent() == Some(path))
+            .map(|(node_path, node)| VfsDirEntry {
+                name: node_path.file_name().unwrap().to_os_string(),
+                file_type: node.meta.file_type,
+            })
+            .collect())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match &self.state().node(path, VfsOp::ReadLink)?.content {
+            MemoryContent::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a symlink", path.display()),
+            )),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        let state = self.state();
+        let mut path = path.to_path_buf();
+        // same limit as linux
+        for _ in 0..40 {
+            match &state.node(&path, VfsOp::Open)?.content {
+                MemoryContent::File(data) => return Ok(Box::new(Cursor::new(data.clone()))),
+                MemoryContent::Symlink(target) => {
+                    path = path.parent().unwrap_or(&path).join(target);
+                }
+                MemoryContent::Empty => {
+                    return Err(io::Error::other(format!(
+                        "{} is not a file",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        Err(io::Error::other(format!(
+            "too many levels of symlinks at {}",
+            path.display()
+        )))
+    }
+
+    fn now(&self) -> SystemTime {
+        self.state().now
+    }
+}
@@ -0,0 +1,790 @@
+use std::{
+    fs::File,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{
+        mpsc::{channel, sync_channel},
+        Arc,
+    },
+    thread,
+};
+
+use rusqlite::{params, Connection, OpenFlags};
+use sausage::{
+    list_sessions, rollback_before_session_id, set_sqlite_item_codec, setup_sqlite_cache,
+    sqlite_item_codec, sqlite_schema_version, ChangeAction, ChangeEvent, ChangeNotifier,
+    EntryCounts, ExcludeRules, FsNode, FsNodeType, ItemCodec, MemoizedFsWalker, SessionReport,
+    SinkWatcher, SpecialKind, StdFs, SQLITE_SCHEMA_VERSION,
+};
+use serde::Serialize;
+
+mod common;
+use common::*;
+
+use anyhow::Result;
+
+/// `run_notifier` on the standard file system, in a transaction of `db`
+fn run_sqlite_notifier(
+    db: &mut Connection,
+    path: impl AsRef<Path>,
+    exclude: ExcludeRules,
+) -> Result<(String, SessionReport)> {
+    let tx = db.transaction()?;
+    let walker = MemoizedFsWalker::new(&*tx).exclude(exclude);
+    let result = run_notifier(walker, Arc::new(StdFs), path)?;
+    tx.commit()?;
+    Ok(result)
+}
+
+#[test]
+fn test_exclude() -> Result<()> {
+    let tmpdir = new_tmpdir("test_exclude")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let mut f = File::create(testdir.join(".sausageignore"))?;
+    writeln!(&mut f, "!f2")?;
+
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+
+    // CHECK\
+    let rules = ExcludeRules::gitignore()
+        .add_pattern("d2/")
+        .add_pattern("f*");
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, rules)?;
+    let expected = r#"
+changed|D|asset
+removed|D|asset/d2/d3
+removed|F|asset/d2/f3
+removed|S|asset/d2/s1
+removed|D|asset/d2
+removed|F|asset/f1
+"#;
+    assert_eq!(
+        result, expected,
+        "\nresult: \n{}\nexpected: \n{}",
+        result, expected
+    );
+    Ok(())
+}
+
+#[test]
+fn test_session_report() -> Result<()> {
+    let tmpdir = new_tmpdir("test_session_report")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+
+    let (_, report) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    let added = |added| EntryCounts {
+        added,
+        ..Default::default()
+    };
+    assert_eq!(report.changes.files, added(3));
+    assert_eq!(report.changes.symlinks, added(1));
+    assert_eq!(report.changes.folders, added(4));
+    assert_eq!((report.cache_hits, report.cache_misses), (0, 8));
+    assert!(report.has_changes());
+
+    update_asset_full_1(&testdir)?;
+
+    // CHECK\
+    let (_, report) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    let changes = report.changes;
+    assert_eq!(
+        changes.files,
+        EntryCounts {
+            added: 1,
+            changed: 1,
+            removed: 1,
+            moved: 0,
+            unchanged: 1
+        }
+    );
+    // the content of d2 is removed along with it
+    assert_eq!(
+        changes.symlinks,
+        EntryCounts {
+            removed: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        changes.folders,
+        EntryCounts {
+            added: 0,
+            changed: 1,
+            removed: 2,
+            moved: 0,
+            unchanged: 1
+        }
+    );
+    assert_eq!((report.cache_hits, report.cache_misses), (2, 3));
+    // the notifier only stats the files
+    assert_eq!(report.bytes_read, 0);
+    assert!(report.errors.is_empty());
+
+    let (_, report) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert!(!report.has_changes());
+    assert_eq!((report.cache_hits, report.cache_misses), (5, 0));
+    Ok(())
+}
+
+#[test]
+fn test_dry_run() -> Result<()> {
+    let tmpdir = new_tmpdir("test_dry_run")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    update_asset_full_1(&testdir)?;
+
+    // CHECK\
+    let count_rows = |db: &Connection| -> Result<(u32, u32)> {
+        Ok(db.query_row(
+            "SELECT (SELECT COUNT(*) FROM fs_walker_sessions), (SELECT COUNT(*) FROM fs_walker_cache)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?)
+    };
+    let before = count_rows(&db)?;
+    let expected = r#"
+changed|F|asset/f1
+added|F|asset/f4
+changed|D|asset
+removed|D|asset/d2/d3
+removed|F|asset/d2/f3
+removed|S|asset/d2/s1
+removed|D|asset/d2
+"#;
+    for _ in 0..2 {
+        let mut acc = Vec::with_capacity(4096);
+        let proc = ChangeNotifier::new(TestWatcher::new(&mut acc)?);
+        let walker = MemoizedFsWalker::new(&db).dry_run(true);
+        let mut adder = walker.start_processing(proc)?;
+        adder.add_path(&testdir, testdir.file_name().unwrap())?;
+        let (_, report) = adder.finish_processing()?;
+        assert_eq!(report.session_id, 2);
+        let result = String::from_utf8(acc)?;
+        assert_eq!(
+            result, expected,
+            "\nresult: \n{}\nexpected: \n{}",
+            result, expected
+        );
+        assert_eq!(count_rows(&db)?, before);
+    }
+
+    // the changes are still seen by a regular session
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_dry_run_read_only() -> Result<()> {
+    let tmpdir = new_tmpdir("test_dry_run_read_only")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    drop(db);
+    update_asset_full_1(&testdir)?;
+
+    // CHECK\
+    // a dry run works on a database opened read only, like `sausage status` does
+    let db = Connection::open_with_flags(
+        tmpdir.path().join("cache.db"),
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    let mut acc = Vec::with_capacity(4096);
+    let proc = ChangeNotifier::new(TestWatcher::new(&mut acc)?);
+    let walker = MemoizedFsWalker::new(&db).dry_run(true);
+    let mut adder = walker.start_processing(proc)?;
+    adder.add_path(&testdir, testdir.file_name().unwrap())?;
+    let (_, report) = adder.finish_processing()?;
+    assert_eq!(report.session_id, 2);
+    assert!(String::from_utf8(acc)?.contains("changed|F|asset/f1"));
+    // while a regular session can not start
+    let walker = MemoizedFsWalker::new(&db);
+    assert!(walker
+        .start_processing(ChangeNotifier::new(TestWatcher::new(Vec::new())?))
+        .is_err());
+    Ok(())
+}
+
+/// path, mtime, session id and item of a cache row
+type CacheRow = (Vec<u8>, i64, i64, u32, Vec<u8>);
+
+#[test]
+fn test_rollback() -> Result<()> {
+    let tmpdir = new_tmpdir("test_rollback")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let dump = |db: &Connection| -> Result<Vec<CacheRow>> {
+        let mut stmt = db.prepare(
+            "SELECT path, mtime_sec, mtime_nano, session_id, item FROM fs_walker_cache ORDER BY path",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    };
+
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    let session_1 = dump(&db)?;
+    update_asset_full_1(&testdir)?;
+    let (session_2_changes, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    File::create(testdir.join("f5"))?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+
+    // CHECK\
+    rollback_before_session_id(&db, "", 2)?;
+    assert_eq!(dump(&db)?, session_1);
+    // f5 is now part of the changes since session 1
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    let expected =
+        session_2_changes.replace("added|F|asset/f4\n", "added|F|asset/f4\nadded|F|asset/f5\n");
+    assert_eq!(
+        result, expected,
+        "\nresult: \n{}\nexpected: \n{}",
+        result, expected
+    );
+    Ok(())
+}
+
+#[test]
+fn test_last_seen_pruning() -> Result<()> {
+    let tmpdir = new_tmpdir("test_last_seen_pruning")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    // path, session id and last seen session of the cache rows
+    let dump = |db: &Connection, table: &str| -> Result<Vec<(String, u32, u32)>> {
+        let column = match table {
+            "fs_walker_cache" => "last_seen_session",
+            _ => "replaced_session_id",
+        };
+        let mut stmt = db.prepare(&format!(
+            "SELECT path, session_id, {} FROM {} ORDER BY path",
+            column, table
+        ))?;
+        let prefix = testdir.to_string_lossy().into_owned();
+        let rows = stmt.query_map([], |row| {
+            let path: Vec<u8> = row.get(0)?;
+            let path = String::from_utf8_lossy(&path).replace(&prefix, "asset");
+            Ok((path, row.get(1)?, row.get(2)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    };
+    let row = |path: &str, session_id, last_seen| (path.to_string(), session_id, last_seen);
+
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    update_asset_full_1(&testdir)?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+
+    // CHECK\
+    // unchanged entries are stamped by the session, the entries it did not see are moved to the history
+    assert_eq!(
+        dump(&db, "fs_walker_cache")?,
+        vec![
+            row("asset", 2, 2),
+            row("asset/d1", 1, 2),
+            row("asset/f1", 2, 2),
+            row("asset/f2", 1, 2),
+            row("asset/f4", 2, 2),
+        ]
+    );
+    assert_eq!(
+        dump(&db, "fs_walker_cache_history")?,
+        vec![
+            row("asset", 1, 2),
+            row("asset/d2", 1, 2),
+            row("asset/d2/d3", 1, 2),
+            row("asset/d2/f3", 1, 2),
+            row("asset/d2/s1", 1, 2),
+            row("asset/f1", 1, 2),
+        ]
+    );
+
+    // the pruned entries are restored as seen by the last session kept
+    rollback_before_session_id(&db, "", 2)?;
+    assert_eq!(
+        dump(&db, "fs_walker_cache")?,
+        vec![
+            row("asset", 1, 1),
+            row("asset/d1", 1, 1),
+            row("asset/d2", 1, 1),
+            row("asset/d2/d3", 1, 1),
+            row("asset/d2/f3", 1, 1),
+            row("asset/d2/s1", 1, 1),
+            row("asset/f1", 1, 1),
+            row("asset/f2", 1, 1),
+        ]
+    );
+    assert!(dump(&db, "fs_walker_cache_history")?.is_empty());
+    // and pruned again by the next session
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert!(result.contains("removed|D|asset/d2\n"));
+    assert_eq!(dump(&db, "fs_walker_cache")?.len(), 5);
+    Ok(())
+}
+
+#[test]
+fn test_list_sessions() -> Result<()> {
+    let tmpdir = new_tmpdir("test_list_sessions")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+
+    let proc = ChangeNotifier::new(TestWatcher::new(Vec::new())?);
+    let mut adder = MemoizedFsWalker::new(&db).start_processing(proc)?;
+    adder.add_path(testdir.join("d1"), "backup/d1")?;
+    adder.add_path(testdir.join("f1"), "backup/f1")?;
+    adder.set_label("nightly");
+    adder.set_artifact("backup.tar");
+    let started_at = adder.started_at();
+    adder.finish_processing()?;
+
+    // not recorded
+    let proc = ChangeNotifier::new(TestWatcher::new(Vec::new())?);
+    let mut adder = MemoizedFsWalker::new(&db)
+        .dry_run(true)
+        .start_processing(proc)?;
+    adder.add_path(&testdir, "asset")?;
+    adder.finish_processing()?;
+
+    // CHECK\
+    let sessions = list_sessions(&db)?;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].label, None);
+    assert_eq!(sessions[0].roots, vec![(testdir.clone(), "asset".into())]);
+    let session = &sessions[1];
+    assert_eq!(session.session_id, 2);
+    assert_eq!(session.started_at, started_at);
+    assert!(session.ended_at.unwrap() >= started_at);
+    assert_eq!(session.label.as_deref(), Some("nightly"));
+    assert_eq!(session.processor.as_deref(), Some("change_notifier"));
+    assert_eq!(session.artifact.as_deref(), Some("backup.tar"));
+    assert_eq!(
+        session.roots,
+        vec![
+            (testdir.join("d1"), "backup/d1".into()),
+            (testdir.join("f1"), "backup/f1".into())
+        ]
+    );
+
+    rollback_before_session_id(&db, "", 2)?;
+    assert_eq!(list_sessions(&db)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_partial_scope() -> Result<()> {
+    let tmpdir = new_tmpdir("test_partial_scope")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let dir_a = new_asset_full(&tmpdir, "a")?;
+    let dir_b = new_asset_full(&tmpdir, "b")?;
+
+    run_sqlite_notifier(&mut db, &dir_a, ExcludeRules::new())?;
+    run_sqlite_notifier(&mut db, &dir_b, ExcludeRules::new())?;
+
+    // CHECK\
+    // the session on b did not remove the entries of a
+    let (result, _) = run_sqlite_notifier(&mut db, &dir_a, ExcludeRules::new())?;
+    assert_eq!(result, "\n");
+    update_asset_full_1(&dir_b)?;
+    let (result, _) = run_sqlite_notifier(&mut db, &dir_b, ExcludeRules::new())?;
+    let expected = r#"
+changed|F|b/f1
+added|F|b/f4
+changed|D|b
+removed|D|b/d2/d3
+removed|F|b/d2/f3
+removed|S|b/d2/s1
+removed|D|b/d2
+"#;
+    assert_eq!(
+        result, expected,
+        "\nresult: \n{}\nexpected: \n{}",
+        result, expected
+    );
+    // entries removed under b are pruned
+    let cached: u32 = db.query_row(
+        "SELECT COUNT(*) FROM fs_walker_cache WHERE path LIKE ?1",
+        [format!("{}/d2%", dir_b.display())],
+        |row| row.get(0),
+    )?;
+    assert_eq!(cached, 0);
+    Ok(())
+}
+
+#[test]
+fn test_namespaces() -> Result<()> {
+    let tmpdir = new_tmpdir("test_namespaces")?;
+    let db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let run = |namespace: &str| -> Result<String> {
+        let mut acc = Vec::with_capacity(4096);
+        let proc = ChangeNotifier::new(TestWatcher::new(&mut acc)?);
+        let walker = MemoizedFsWalker::new(&db).namespace(namespace);
+        let mut adder = walker.start_processing(proc)?;
+        adder.add_path(&testdir, testdir.file_name().unwrap())?;
+        adder.finish_processing()?;
+        Ok(String::from_utf8(acc)?)
+    };
+
+    run("local")?;
+    update_asset_full_1(&testdir)?;
+    run("local")?;
+
+    // CHECK\
+    // the remote namespace starts from scratch, and is not pruned by the local sessions
+    let remote = run("remote")?;
+    assert!(remote.contains("added|F|asset/f4"));
+    assert!(!remote.contains("changed"));
+    assert_eq!(run("local")?, "\n");
+    assert_eq!(run("remote")?, "\n");
+
+    // rolling back the local namespace keeps the remote sessions
+    rollback_before_session_id(&db, "local", 2)?;
+    let namespaces: Vec<_> = list_sessions(&db)?
+        .into_iter()
+        .map(|session| (session.session_id, session.namespace))
+        .collect();
+    assert_eq!(
+        namespaces,
+        vec![
+            (1, "local".to_string()),
+            (3, "remote".to_string()),
+            (5, "remote".to_string())
+        ]
+    );
+    assert!(run("local")?.contains("added|F|asset/f4"));
+    assert_eq!(run("remote")?, "\n");
+    Ok(())
+}
+
+#[test]
+fn test_non_utf8_names() -> Result<()> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let tmpdir = new_tmpdir("test_non_utf8_names")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    // both names are "f\u{FFFD}" once converted lossily
+    File::create(testdir.join(OsStr::from_bytes(b"f\xfe")))?;
+    File::create(testdir.join(OsStr::from_bytes(b"f\xff")))?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+
+    // CHECK\
+    let cached: u32 = db.query_row(
+        "SELECT COUNT(*) FROM fs_walker_cache WHERE path IN (?1, ?2)",
+        [
+            testdir
+                .join(OsStr::from_bytes(b"f\xfe"))
+                .as_os_str()
+                .as_bytes(),
+            testdir
+                .join(OsStr::from_bytes(b"f\xff"))
+                .as_os_str()
+                .as_bytes(),
+        ],
+        |row| row.get(0),
+    )?;
+    assert_eq!(cached, 2);
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(result, "\n");
+
+    std::fs::remove_file(testdir.join(OsStr::from_bytes(b"f\xfe")))?;
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    let expected = "\nchanged|D|asset\nremoved|F|asset/f\u{FFFD}\n";
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+/// `FsNode` as cached up to the schema version 4, without the metadata of the entries
+#[derive(Serialize)]
+enum OldFsNode {
+    File,
+    Symlink,
+    Folder(Vec<(Vec<u8>, FsNodeType)>),
+    Special(SpecialKind),
+}
+
+/// rewrite the items of the default namespace with the layout of `OldFsNode`
+fn write_old_items(db: &Connection) -> Result<()> {
+    let codec = sqlite_item_codec(db)?;
+    for table in ["fs_walker_cache", "fs_walker_cache_history"] {
+        let items: Vec<(i64, Vec<u8>)> = db
+            .prepare(&format!(
+                "SELECT rowid, item FROM {} WHERE namespace = ''",
+                table
+            ))?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (row_id, item) in items {
+            let old = match codec.decode::<FsNode>(&item)? {
+                FsNode::File(_) => OldFsNode::File,
+                FsNode::Symlink(_) => OldFsNode::Symlink,
+                FsNode::Folder(_) => OldFsNode::Folder(Vec::new()),
+                FsNode::Special(kind, _) => OldFsNode::Special(kind),
+            };
+            db.execute(
+                &format!("UPDATE {} SET item = ?2 WHERE rowid = ?1", table),
+                params![row_id, codec.encode(&old)?],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_schema_migrations() -> Result<()> {
+    let tmpdir = new_tmpdir("test_schema_migrations")?;
+    let mut current = new_sqlite_cache(&tmpdir, "current.db")?;
+    // the items of previous versions were encoded with bincode
+    set_sqlite_item_codec(&current, ItemCodec::Bincode)?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut current, &testdir, ExcludeRules::new())?;
+    write_old_items(&current)?;
+    drop(current);
+
+    // a cache written before the schema versioning, with paths as text
+    let mut db = Connection::open(tmpdir.path().join("cache.db"))?;
+    db.execute(
+        "ATTACH DATABASE ?1 AS current",
+        [tmpdir.path().join("current.db").to_str().unwrap()],
+    )?;
+    db.execute_batch(
+        r#"
+        CREATE TABLE fs_walker_sessions (session_id INTEGER NOT NULL, PRIMARY KEY (session_id));
+        CREATE TABLE fs_walker_cache (
+            path TEXT NOT NULL,
+            mtime_sec INTEGER NOT NULL,
+            mtime_nano INTEGER NOT NULL,
+            session_id INTEGER NOT NULL,
+            item BLOB,
+            PRIMARY KEY (path),
+            FOREIGN KEY (session_id) REFERENCES fs_walker_sessions (session_id)
+        );
+        INSERT INTO fs_walker_sessions SELECT session_id FROM current.fs_walker_sessions;
+        INSERT INTO fs_walker_cache SELECT CAST(path AS TEXT), mtime_sec, mtime_nano, session_id, item
+            FROM current.fs_walker_cache;
+        DETACH DATABASE current;
+    "#,
+    )?;
+    assert_eq!(sqlite_schema_version(&db)?, 1);
+    assert!(run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new()).is_err());
+
+    // CHECK\
+    setup_sqlite_cache(&db)?;
+    assert_eq!(sqlite_schema_version(&db)?, SQLITE_SCHEMA_VERSION);
+    let text_paths: u32 = db.query_row(
+        "SELECT COUNT(*) FROM fs_walker_cache WHERE typeof(path) != 'blob'",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(text_paths, 0);
+    // rows of the old schema were last seen by the session that wrote them
+    let last_seen: Vec<(u32, u32)> = db
+        .prepare("SELECT session_id, last_seen_session FROM fs_walker_cache")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    assert_eq!(last_seen, vec![(1, 1); 8]);
+    let (result, report) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(result, "\n");
+    assert_eq!(report.session_id, 2);
+    // removed entries are pruned from a migrated cache
+    std::fs::remove_file(testdir.join("f2"))?;
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert!(result.contains("removed|F|asset/f2\n"));
+    let f2_rows: (u32, u32) = db.query_row(
+        "SELECT (SELECT COUNT(*) FROM fs_walker_cache WHERE path = ?1),
+            (SELECT COUNT(*) FROM fs_walker_cache_history WHERE path = ?1 AND replaced_session_id = 3)",
+        [testdir.join("f2").as_os_str().as_bytes()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    assert_eq!(f2_rows, (0, 1));
+    // running the setup again is a no-op
+    setup_sqlite_cache(&db)?;
+    assert_eq!(sqlite_schema_version(&db)?, SQLITE_SCHEMA_VERSION);
+    Ok(())
+}
+
+#[test]
+fn test_old_item_layout() -> Result<()> {
+    let tmpdir = new_tmpdir("test_old_item_layout")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    update_asset_full_1(&testdir)?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    // the items of other processors are left as they are
+    {
+        let tx = db.transaction()?;
+        let walker = MemoizedFsWalker::new(&*tx).namespace("other");
+        let mut adder = walker.start_processing(TestProcessor::new(std::io::sink())?)?;
+        adder.add_path(&testdir, "asset")?;
+        adder.finish_processing()?;
+        tx.commit()?;
+    }
+    let other_items = |db: &Connection| -> Result<Vec<Vec<u8>>> {
+        Ok(db
+            .prepare("SELECT item FROM fs_walker_cache WHERE namespace = 'other' ORDER BY path")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    };
+    let other = other_items(&db)?;
+
+    // a cache of schema version 4, holding the items of the previous `FsNode` layout
+    write_old_items(&db)?;
+    db.execute("UPDATE fs_walker_schema_version SET version = 4", [])?;
+    let item: Vec<u8> = db.query_row(
+        "SELECT item FROM fs_walker_cache WHERE path = ?1",
+        [testdir.join("f1").as_os_str().as_bytes()],
+        |row| row.get(0),
+    )?;
+    let codec = sqlite_item_codec(&db)?;
+    assert!(codec.decode::<FsNode>(&item).is_err());
+    assert!(run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new()).is_err());
+
+    // CHECK\
+    setup_sqlite_cache(&db)?;
+    assert_eq!(sqlite_schema_version(&db)?, SQLITE_SCHEMA_VERSION);
+    assert_eq!(other_items(&db)?, other);
+    let item: Vec<u8> = db.query_row(
+        "SELECT item FROM fs_walker_cache WHERE path = ?1",
+        [testdir.join("f1").as_os_str().as_bytes()],
+        |row| row.get(0),
+    )?;
+    assert!(codec.decode::<FsNode>(&item)?.meta().is_none());
+    // the entries are still cached, removed ones are notified
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(result, "\n");
+    std::fs::remove_file(testdir.join("f2"))?;
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(result, "\nchanged|D|asset\nremoved|F|asset/f2\n");
+    // the history of the sessions before the upgrade is kept
+    rollback_before_session_id(&db, "", 2)?;
+    let (result, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert!(result.contains("removed|F|asset/d2/f3\n"), "{}", result);
+    assert!(result.contains("changed|F|asset/f1\n"), "{}", result);
+    Ok(())
+}
+
+#[test]
+fn test_newer_schema() -> Result<()> {
+    let tmpdir = new_tmpdir("test_newer_schema")?;
+    let db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    db.execute(
+        "UPDATE fs_walker_schema_version SET version = ?1",
+        [SQLITE_SCHEMA_VERSION + 1],
+    )?;
+
+    // CHECK\
+    let err = setup_sqlite_cache(&db).unwrap_err();
+    assert!(err.to_string().contains("newer"), "{}", err);
+    assert_eq!(sqlite_schema_version(&db)?, SQLITE_SCHEMA_VERSION + 1);
+    Ok(())
+}
+
+#[test]
+fn test_change_channel() -> Result<()> {
+    let tmpdir = new_tmpdir("test_change_channel")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    update_asset_full_1(&testdir)?;
+
+    // CHECK\
+    let (sender, receiver) = sync_channel(1);
+    let consumer = thread::spawn(move || receiver.iter().collect::<Vec<ChangeEvent>>());
+    let tx = db.transaction()?;
+    {
+        let walker = MemoizedFsWalker::new(&*tx);
+        let mut adder = walker.start_processing(ChangeNotifier::new(sender))?;
+        adder.add_path(&testdir, testdir.file_name().unwrap())?;
+        // the receiver iterator ends once the sender is dropped
+        adder.finish_processing()?;
+    }
+    tx.commit()?;
+    let events = consumer.join().unwrap();
+
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| {
+            (
+                event.kind.clone(),
+                event.action.clone(),
+                event.mount_path.clone(),
+            )
+        })
+        .collect();
+    let asset = Path::new("asset");
+    let expected = vec![
+        (FsNodeType::File, ChangeAction::Changed, asset.join("f1")),
+        (FsNodeType::File, ChangeAction::Added, asset.join("f4")),
+        (
+            FsNodeType::Folder,
+            ChangeAction::Changed,
+            asset.to_path_buf(),
+        ),
+        (
+            FsNodeType::Folder,
+            ChangeAction::Removed,
+            asset.join("d2/d3"),
+        ),
+        (FsNodeType::File, ChangeAction::Removed, asset.join("d2/f3")),
+        (
+            FsNodeType::Symlink,
+            ChangeAction::Removed,
+            asset.join("d2/s1"),
+        ),
+        (FsNodeType::Folder, ChangeAction::Removed, asset.join("d2")),
+    ];
+    assert_eq!(summary, expected);
+    // the current metadata of the changed file, the cached one of the removed entries
+    let meta = |index: usize| events[index].meta.clone().unwrap();
+    assert_eq!(meta(0).size, "changed\n".len() as u64);
+    assert_eq!(meta(1).size, 0);
+    assert_eq!(events[0].path, testdir.join("f1"));
+
+    // the walk fails once nobody listens anymore
+    File::create(testdir.join("f5"))?;
+    let (sender, receiver) = channel();
+    drop(receiver);
+    let walker = MemoizedFsWalker::new(&db).dry_run(true);
+    let mut adder = walker.start_processing(ChangeNotifier::new(sender))?;
+    assert!(adder
+        .add_path(&testdir, testdir.file_name().unwrap())
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_sink_watcher() -> Result<()> {
+    let tmpdir = new_tmpdir("test_sink_watcher")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    update_asset_full_1(&testdir)?;
+
+    // CHECK\
+    // events turned back into notifications print the same lines as the watcher alone
+    let mut acc = Vec::with_capacity(4096);
+    let proc = ChangeNotifier::new(SinkWatcher(TestWatcher::new(&mut acc)?));
+    let walker = MemoizedFsWalker::new(&db).dry_run(true);
+    let mut adder = walker.start_processing(proc)?;
+    adder.add_path(&testdir, testdir.file_name().unwrap())?;
+    adder.finish_processing()?;
+    let result = String::from_utf8(acc)?;
+    let (expected, _) = run_sqlite_notifier(&mut db, &testdir, ExcludeRules::new())?;
+    assert_eq!(
+        result, expected,
+        "\nresult: \n{}\nexpected: \n{}",
+        result, expected
+    );
+    Ok(())
+}
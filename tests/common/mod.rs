@@ -1,6 +1,14 @@
 mod test_processor;
+#[allow(unused_imports)]
 pub use test_processor::TestProcessor;
 
+mod test_watcher;
+#[allow(unused_imports)]
+pub use test_watcher::TestWatcher;
+
+mod test_notifier;
+#[allow(unused_imports)]
+pub use test_notifier::run_notifier;
+
 mod test_folder;
 pub use test_folder::*;
-//dfdsfa
@@ -4,20 +4,28 @@ use std::{
     fs::{create_dir, create_dir_all, remove_dir_all, remove_file, File},
     os::unix::fs::symlink,
     path::{Path, PathBuf},
+    sync::Once,
+    time::Duration,
 };
 
 use anyhow::Result;
 use rusqlite::Connection;
-use sausage::setup_sqlite_cache;
+use sausage::{setup_sqlite_cache, MemoryFs};
 use std::io::Write;
 use tempdir::TempDir;
 
 pub fn get_test_trash() -> Result<PathBuf> {
+    // tests of a binary run in parallel, the trash is emptied only before the first one
+    static CLEAN: Once = Once::new();
     let path = PathBuf::from("trash");
-    if path.exists() {
-        remove_dir_all(&path)?;
-    }
-    create_dir(&path)?;
+    let mut cleaned = Ok(());
+    CLEAN.call_once(|| {
+        if path.exists() {
+            cleaned = remove_dir_all(&path);
+        }
+    });
+    cleaned?;
+    create_dir_all(&path)?;
     Ok(path)
 }
 
@@ -65,3 +73,26 @@ pub fn update_asset_full_1(path: &Path) -> Result<()> {
 
     Ok(())
 }
+
+/// same tree as `new_asset_full`, in a `MemoryFs`
+pub fn new_memory_asset_full(fs: &MemoryFs, path: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    fs.create_dir_all(path.join("d1"))?;
+    fs.create_dir_all(path.join("d2/d3"))?;
+
+    fs.write(path.join("f1"), "")?;
+    fs.write(path.join("f2"), "")?;
+    fs.write(path.join("d2/f3"), "")?;
+
+    fs.symlink(path.join("d2/f3"), path.join("d2/s1"))?;
+    Ok(path.to_path_buf())
+}
+
+/// same changes as `update_asset_full_1`, the clock is moved forward first
+pub fn update_memory_asset_full_1(fs: &MemoryFs, path: &Path) -> Result<()> {
+    fs.advance(Duration::from_secs(1));
+    fs.write(path.join("f4"), "")?;
+    fs.write(path.join("f1"), "changed\n")?;
+    fs.remove(path.join("d2"))?;
+    Ok(())
+}
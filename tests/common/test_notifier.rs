@@ -0,0 +1,22 @@
+#![allow(unused)]
+use anyhow::Result;
+use sausage::{ChangeNotifier, FsNode, MemoizedFsCache, MemoizedFsWalker, SessionReport, Vfs};
+use std::{path::Path, sync::Arc};
+
+use super::TestWatcher;
+
+/// walk `path` with a `ChangeNotifier` reading from `vfs`, which is also given to `walker`.
+/// Return the changes written by a `TestWatcher` along with the session report
+pub fn run_notifier<C: MemoizedFsCache<FsNode>>(
+    walker: MemoizedFsWalker<FsNode, C>,
+    vfs: Arc<dyn Vfs>,
+    path: impl AsRef<Path>,
+) -> Result<(String, SessionReport)> {
+    let path = path.as_ref();
+    let mut acc = Vec::with_capacity(4096);
+    let proc = ChangeNotifier::with_vfs(TestWatcher::new(&mut acc)?, vfs.clone());
+    let mut adder = walker.vfs(vfs).start_processing(proc)?;
+    adder.add_path(path, path.file_name().unwrap())?;
+    let (_, report) = adder.finish_processing()?;
+    Ok((String::from_utf8(acc)?, report))
+}
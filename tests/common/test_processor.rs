@@ -1,6 +1,6 @@
 #![allow(unused)]
 use anyhow::Result;
-use sausage::{FsEntry, FsProcessor};
+use sausage::{FsEntry, FsProcessor, SpecialKind};
 use std::{collections::HashMap, path::Path};
 use std::{io::Write, path::PathBuf};
 
@@ -38,6 +38,17 @@ impl<W: Write> FsProcessor for TestProcessor<W> {
         Ok(None)
     }
 
+    fn process_special(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        writeln!(&mut self.acc, "X|{}|{:?}", path.to_string_lossy(), kind)?;
+        Ok(None)
+    }
+
     fn process_folder(
         &mut self,
         path: &Path,
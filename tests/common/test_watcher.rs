@@ -0,0 +1,153 @@
+#![allow(unused)]
+use anyhow::Result;
+use sausage::{FsChangeWatcher, FsNodeType, SpecialKind};
+use std::{io::Write, path::Path};
+
+/// write one line per notification: `<action>|<kind>|<mount path>`, `metadata` being a metadata-only change, `moved|<kind>|<from mount path>-><mount path>`
+pub struct TestWatcher<W: Write> {
+    acc: W,
+}
+
+impl<W: Write> TestWatcher<W> {
+    pub fn new(mut acc: W) -> Result<Self> {
+        writeln!(&mut acc)?; // to help with r#"..."#
+        Ok(TestWatcher { acc })
+    }
+
+    fn notify(&mut self, action: &str, kind: &str, mount_path: &Path) -> Result<()> {
+        writeln!(
+            &mut self.acc,
+            "{}|{}|{}",
+            action,
+            kind,
+            mount_path.to_string_lossy()
+        )?;
+        Ok(())
+    }
+
+    fn notify_moved(
+        &mut self,
+        kind: &str,
+        from_mount_path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        writeln!(
+            &mut self.acc,
+            "moved|{}|{}->{}",
+            kind,
+            from_mount_path.to_string_lossy(),
+            mount_path.to_string_lossy()
+        )?;
+        Ok(())
+    }
+}
+
+impl<W: Write> FsChangeWatcher for TestWatcher<W> {
+    fn notify_file_added(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("added", "F", mount_path)
+    }
+    fn notify_file_changed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("changed", "F", mount_path)
+    }
+    fn notify_file_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("removed", "F", mount_path)
+    }
+
+    fn notify_symlink_added(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("added", "S", mount_path)
+    }
+    fn notify_symlink_changed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("changed", "S", mount_path)
+    }
+    fn notify_symlink_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("removed", "S", mount_path)
+    }
+
+    fn notify_folder_added(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("added", "D", mount_path)
+    }
+    fn notify_folder_changed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("changed", "D", mount_path)
+    }
+    fn notify_folder_removed(&mut self, _path: &Path, mount_path: &Path) -> Result<()> {
+        self.notify("removed", "D", mount_path)
+    }
+
+    fn notify_special_added(
+        &mut self,
+        _path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        self.notify("added", &format!("X:{:?}", kind), mount_path)
+    }
+    fn notify_special_changed(
+        &mut self,
+        _path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        self.notify("changed", &format!("X:{:?}", kind), mount_path)
+    }
+    fn notify_special_removed(
+        &mut self,
+        _path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        self.notify("removed", &format!("X:{:?}", kind), mount_path)
+    }
+
+    fn notify_metadata_changed(
+        &mut self,
+        _path: &Path,
+        mount_path: &Path,
+        node_type: FsNodeType,
+    ) -> Result<()> {
+        let kind = match node_type {
+            FsNodeType::File => "F".to_string(),
+            FsNodeType::Symlink => "S".to_string(),
+            FsNodeType::Folder => "D".to_string(),
+            FsNodeType::Special(kind) => format!("X:{:?}", kind),
+        };
+        self.notify("metadata", &kind, mount_path)
+    }
+
+    fn notify_file_moved(
+        &mut self,
+        _from: &Path,
+        from_mount_path: &Path,
+        _path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.notify_moved("F", from_mount_path, mount_path)
+    }
+    fn notify_symlink_moved(
+        &mut self,
+        _from: &Path,
+        from_mount_path: &Path,
+        _path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.notify_moved("S", from_mount_path, mount_path)
+    }
+    fn notify_folder_moved(
+        &mut self,
+        _from: &Path,
+        from_mount_path: &Path,
+        _path: &Path,
+        mount_path: &Path,
+    ) -> Result<()> {
+        self.notify_moved("D", from_mount_path, mount_path)
+    }
+    fn notify_special_moved(
+        &mut self,
+        _from: &Path,
+        from_mount_path: &Path,
+        _path: &Path,
+        mount_path: &Path,
+        kind: SpecialKind,
+    ) -> Result<()> {
+        self.notify_moved(&format!("X:{:?}", kind), from_mount_path, mount_path)
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fs::File, sync::Arc};
+
+use rusqlite::Connection;
+use sausage::{rollback_before_session_id, MemoizedFsWalker, MemoryCache, StdFs};
+
+mod common;
+use common::*;
+
+use anyhow::Result;
+
+#[test]
+fn test_same_as_sqlite() -> Result<()> {
+    let tmpdir = new_tmpdir("test_same_as_sqlite")?;
+    let db: Connection = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let mut memory = MemoryCache::new();
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let mut run = |namespace: &str| -> Result<String> {
+        let (from_sqlite, _) = run_notifier(
+            MemoizedFsWalker::new(&db).namespace(namespace),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        let (from_memory, _) = run_notifier(
+            MemoizedFsWalker::new(&mut memory).namespace(namespace),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        assert_eq!(from_sqlite, from_memory);
+        Ok(from_memory)
+    };
+
+    run("")?;
+    update_asset_full_1(&testdir)?;
+    let changes = run("")?;
+    assert!(changes.contains("removed|D|asset/d2"));
+    run("other")?;
+    assert_eq!(run("")?, "\n");
+    assert_eq!(run("other")?, "\n");
+    File::create(testdir.join("f5"))?;
+
+    // CHECK\
+    rollback_before_session_id(&db, "", 2)?;
+    memory.rollback_before_session_id("", 2);
+    let sessions: Vec<_> = memory
+        .sessions()
+        .iter()
+        .map(|session| (session.session_id, session.namespace.as_str()))
+        .collect();
+    assert_eq!(sessions, vec![(1, ""), (3, "other"), (5, "other")]);
+    let mut run = |namespace: &str| -> Result<String> {
+        let (from_sqlite, _) = run_notifier(
+            MemoizedFsWalker::new(&db).namespace(namespace),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        let (from_memory, _) = run_notifier(
+            MemoizedFsWalker::new(&mut memory).namespace(namespace),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        assert_eq!(from_sqlite, from_memory);
+        Ok(from_memory)
+    };
+    let expected = changes.replace("added|F|asset/f4\n", "added|F|asset/f4\nadded|F|asset/f5\n");
+    assert_eq!(run("")?, expected);
+    assert_eq!(run("other")?, "\nadded|F|asset/f5\nchanged|D|asset\n");
+    Ok(())
+}
+
+#[test]
+fn test_partial_scope() -> Result<()> {
+    let tmpdir = new_tmpdir("test_memory_partial_scope")?;
+    let mut memory = MemoryCache::new();
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_notifier(
+        MemoizedFsWalker::new(&mut memory),
+        Arc::new(StdFs),
+        &testdir,
+    )?;
+    let cached = memory.len("");
+
+    // CHECK\
+    // a session walking d2 only does not prune the rest of the tree
+    run_notifier(
+        MemoizedFsWalker::new(&mut memory),
+        Arc::new(StdFs),
+        testdir.join("d2"),
+    )?;
+    assert_eq!(memory.len(""), cached);
+    assert_eq!(
+        run_notifier(
+            MemoizedFsWalker::new(&mut memory),
+            Arc::new(StdFs),
+            &testdir
+        )?
+        .0,
+        "\n"
+    );
+    assert!(memory.is_empty("other"));
+    Ok(())
+}
+
+#[cfg(feature = "snapshot")]
+#[test]
+fn test_snapshot() -> Result<()> {
+    let tmpdir = new_tmpdir("test_snapshot")?;
+    let mut memory = MemoryCache::new();
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    run_notifier(
+        MemoizedFsWalker::new(&mut memory),
+        Arc::new(StdFs),
+        &testdir,
+    )?;
+    let snapshot = tmpdir.path().join("cache.bin");
+    memory.save_snapshot(&snapshot)?;
+
+    // CHECK\
+    let mut restored = MemoryCache::load_snapshot(&snapshot)?;
+    assert_eq!(restored.sessions(), memory.sessions());
+    assert_eq!(restored.len(""), memory.len(""));
+    assert_eq!(
+        run_notifier(
+            MemoizedFsWalker::new(&mut restored),
+            Arc::new(StdFs),
+            &testdir
+        )?
+        .0,
+        "\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,178 @@
+#![cfg(feature = "redb")]
+use std::{fs::File, sync::Arc};
+
+use rusqlite::Connection;
+use sausage::{rollback_before_session_id, ItemCodec, MemoizedFsWalker, RedbCache, StdFs};
+
+mod common;
+use common::*;
+
+use anyhow::Result;
+
+#[test]
+fn test_same_as_sqlite() -> Result<()> {
+    let tmpdir = new_tmpdir("test_redb_same_as_sqlite")?;
+    let db: Connection = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let redb = RedbCache::open(tmpdir.path().join("cache.redb"))?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let run = |namespace: &str, dry_run: bool| -> Result<String> {
+        let (from_sqlite, _) = run_notifier(
+            MemoizedFsWalker::new(&db)
+                .namespace(namespace)
+                .dry_run(dry_run),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        let (from_redb, _) = run_notifier(
+            MemoizedFsWalker::new(&redb)
+                .namespace(namespace)
+                .dry_run(dry_run),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        assert_eq!(from_sqlite, from_redb);
+        Ok(from_redb)
+    };
+
+    run("", false)?;
+    update_asset_full_1(&testdir)?;
+    let changes = run("", true)?;
+    assert_eq!(run("", false)?, changes);
+    assert!(changes.contains("removed|D|asset/d2"));
+    run("other", false)?;
+    assert_eq!(run("", false)?, "\n");
+    File::create(testdir.join("f5"))?;
+    // a partial scope does not prune the rest of the tree
+    run_notifier(
+        MemoizedFsWalker::new(&db).namespace("other"),
+        Arc::new(StdFs),
+        testdir.join("d1"),
+    )?;
+    run_notifier(
+        MemoizedFsWalker::new(&redb).namespace("other"),
+        Arc::new(StdFs),
+        testdir.join("d1"),
+    )?;
+
+    // CHECK\
+    rollback_before_session_id(&db, "", 2)?;
+    redb.rollback_before_session_id("", 2)?;
+    let sessions: Vec<_> = redb
+        .list_sessions()?
+        .into_iter()
+        .map(|session| (session.session_id, session.namespace))
+        .collect();
+    assert_eq!(
+        sessions,
+        vec![
+            (1, "".to_string()),
+            (3, "other".to_string()),
+            (5, "other".to_string())
+        ]
+    );
+    let expected = changes.replace("added|F|asset/f4\n", "added|F|asset/f4\nadded|F|asset/f5\n");
+    assert_eq!(run("", false)?, expected);
+    assert_eq!(
+        run("other", false)?,
+        "\nadded|F|asset/f5\nchanged|D|asset\n"
+    );
+
+    // the history is still needed to go back to the session 5 of the other namespace
+    redb.prune_history_before_session_id(6)?;
+    redb.rollback_before_session_id("other", 6)?;
+    assert_eq!(
+        run_notifier(
+            MemoizedFsWalker::new(&redb).namespace("other"),
+            Arc::new(StdFs),
+            &testdir
+        )?
+        .0,
+        "\nadded|F|asset/f5\nchanged|D|asset\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_moves_same_as_sqlite() -> Result<()> {
+    let tmpdir = new_tmpdir("test_redb_moves_same_as_sqlite")?;
+    let db: Connection = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let redb = RedbCache::open(tmpdir.path().join("cache.redb"))?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let run = || -> Result<String> {
+        let (from_sqlite, _) = run_notifier(
+            MemoizedFsWalker::new(&db).detect_moves(true),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        let (from_redb, _) = run_notifier(
+            MemoizedFsWalker::new(&redb).detect_moves(true),
+            Arc::new(StdFs),
+            &testdir,
+        )?;
+        assert_eq!(from_sqlite, from_redb);
+        Ok(from_redb)
+    };
+
+    run()?;
+    std::fs::rename(testdir.join("d2"), testdir.join("d1/d4"))?;
+
+    // CHECK\
+    let changes = run()?;
+    assert!(changes.contains("moved|D|asset/d2->asset/d1/d4\n"));
+    assert!(!changes.contains("added|") && !changes.contains("removed|"));
+    assert_eq!(run()?, "\n");
+    // the entries are back at their old paths and moved again
+    rollback_before_session_id(&db, "", 2)?;
+    redb.rollback_before_session_id("", 2)?;
+    assert_eq!(run()?, changes);
+    Ok(())
+}
+
+#[test]
+fn test_reopen() -> Result<()> {
+    let tmpdir = new_tmpdir("test_redb_reopen")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let path = tmpdir.path().join("cache.redb");
+    {
+        let redb = RedbCache::open(&path)?;
+        run_notifier(MemoizedFsWalker::new(&redb), Arc::new(StdFs), &testdir)?;
+    }
+
+    // CHECK\
+    let redb = RedbCache::open(&path)?;
+    assert_eq!(redb.list_sessions()?.len(), 1);
+    assert_eq!(
+        run_notifier(MemoizedFsWalker::new(&redb), Arc::new(StdFs), &testdir)?.0,
+        "\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_item_codec() -> Result<()> {
+    let tmpdir = new_tmpdir("test_redb_item_codec")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let path = tmpdir.path().join("cache.redb");
+    #[cfg(feature = "json")]
+    let codec = ItemCodec::Json;
+    #[cfg(not(feature = "json"))]
+    let codec = ItemCodec::Bincode;
+    {
+        let mut redb = RedbCache::open(&path)?;
+        assert_eq!(redb.item_codec(), ItemCodec::default());
+        redb.set_item_codec(codec)?;
+        run_notifier(MemoizedFsWalker::new(&redb), Arc::new(StdFs), &testdir)?;
+    }
+
+    // CHECK\
+    let mut redb = RedbCache::open(&path)?;
+    assert_eq!(redb.item_codec(), codec);
+    if codec != ItemCodec::Bincode {
+        assert!(redb.set_item_codec(ItemCodec::Bincode).is_err());
+    }
+    assert_eq!(
+        run_notifier(MemoizedFsWalker::new(&redb), Arc::new(StdFs), &testdir)?.0,
+        "\n"
+    );
+    Ok(())
+}
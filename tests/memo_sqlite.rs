@@ -1,4 +1,23 @@
-use sausage::MemoizedFsWalker;
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use sausage::{
+    set_sqlite_item_codec, sqlite_item_codec, CacheLookup, ChangeDetector, FsEntry, FsProcessor,
+    FsStat, ItemCodec, MemoizedFsCache, MemoizedFsCacheSession, MemoizedFsWalker,
+    ParallelFsProcessor, SpecialKind,
+};
+use serde::{Deserialize, Serialize};
 
 mod common;
 use common::*;
@@ -27,13 +46,19 @@ fn test1() -> Result<()> {
 
     // CHECK\
     let result = std::str::from_utf8(&acc)?;
-    let expected = r#"
-F|testing/a
-F|testing/1/b
-D|testing/1|1
-S|testing/c
-D|testing|3
-"#;
+    let expected = format!(
+        r#"
+D|{0}/d1|0
+D|{0}/d2/d3|0
+F|{0}/d2/f3
+S|{0}/d2/s1
+D|{0}/d2|3
+F|{0}/f1
+F|{0}/f2
+D|{0}|4
+"#,
+        testdir.display()
+    );
     assert_eq!(
         result, expected,
         "\nresult: \n{}\nexpected: \n{}",
@@ -41,3 +66,498 @@ D|testing|3
     );
     Ok(())
 }
+
+#[test]
+fn test_propagate_changes() -> Result<()> {
+    let tmpdir = new_tmpdir("test_propagate_changes")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let mut acc = Vec::with_capacity(4096);
+    for step in 0..2 {
+        if step == 1 {
+            sleep(Duration::from_secs(1)); //wait for FS modification to be visible, we are too fast!
+            let mut f = File::create(testdir.join("d2/f3"))?;
+            writeln!(&mut f, "changed")?;
+            acc.clear();
+        }
+        let proc = TestProcessor::new(&mut acc)?;
+        let tx = db.transaction()?;
+        let walker = MemoizedFsWalker::new(&*tx).propagate_changes(true);
+        let mut adder = walker.start_processing(proc)?;
+        let _ = adder.add_path(&testdir, testdir.file_name().unwrap())?;
+        let _ = adder.finish_processing()?;
+        tx.commit()?;
+    }
+
+    // CHECK\
+    let result = std::str::from_utf8(&acc)?;
+    let expected = format!(
+        r#"
+F|{0}/d2/f3
+D|{0}/d2|3
+D|{0}|4
+"#,
+        testdir.display()
+    );
+    assert_eq!(
+        result, expected,
+        "\nresult: \n{}\nexpected: \n{}",
+        result, expected
+    );
+    Ok(())
+}
+
+/// sums file sizes, folders get the size of their content
+#[derive(Default)]
+struct SizeProcessor {
+    processed_files: Arc<AtomicUsize>,
+    fail_on: Option<PathBuf>,
+}
+
+impl FsProcessor for SizeProcessor {
+    type Item = i64;
+
+    fn process_file(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        self.process_file_shared(path, mount_path, previous)
+    }
+
+    fn process_symlink(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        Ok(0)
+    }
+
+    fn process_special(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _kind: SpecialKind,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        Ok(0)
+    }
+
+    fn process_folder(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        sub: HashMap<PathBuf, FsEntry<Self::Item>>,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        Ok(sub.values().map(|entry| entry.item).sum())
+    }
+}
+
+impl ParallelFsProcessor for SizeProcessor {
+    fn process_file_shared(
+        &self,
+        path: &Path,
+        _mount_path: &Path,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        if path.file_name() == self.fail_on.as_deref().map(Path::as_os_str) {
+            anyhow::bail!("can not process {}", path.display());
+        }
+        self.processed_files.fetch_add(1, Ordering::SeqCst);
+        Ok(path.metadata()?.len() as i64)
+    }
+}
+
+#[test]
+fn test_parallel() -> Result<()> {
+    let tmpdir = new_tmpdir("test_parallel")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    update_asset_full_1(&testdir)?;
+    create_dir_all(testdir.join("d4/d5"))?;
+    let mut f = File::create(testdir.join("d4/d5/f5"))?;
+    writeln!(&mut f, "some content")?;
+
+    // a sequential run followed by parallel runs must hit the cache
+    let mut results = Vec::new();
+    for parallel in [false, true, true] {
+        let proc = SizeProcessor::default();
+        let processed_files = proc.processed_files.clone();
+        let tx = db.transaction()?;
+        let walker = MemoizedFsWalker::new(&*tx).parallelism(4);
+        let mut adder = walker.start_processing(proc)?;
+        let entry = if parallel {
+            adder.add_path_parallel(&testdir, testdir.file_name().unwrap())?
+        } else {
+            adder.add_path(&testdir, testdir.file_name().unwrap())?
+        };
+        let _ = adder.finish_processing()?;
+        tx.commit()?;
+        results.push((entry.item, processed_files.load(Ordering::SeqCst)));
+    }
+    assert_eq!(results, vec![(21, 4), (21, 0), (21, 0)]);
+
+    // a parallel run on an empty cache gives the same result
+    let mut other_db = new_sqlite_cache(&tmpdir, "other.db")?;
+    let tx = other_db.transaction()?;
+    let walker = MemoizedFsWalker::new(&*tx);
+    let mut adder = walker.start_processing(SizeProcessor::default())?;
+    let entry = adder.add_path_parallel(&testdir, testdir.file_name().unwrap())?;
+    assert_eq!(entry.item, 21);
+    let _ = adder.finish_processing()?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_change_detector() -> Result<()> {
+    let tmpdir = new_tmpdir("test_change_detector")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+
+    let mut results = Vec::new();
+    for detector in [ChangeDetector::Mtime, ChangeDetector::ContentHash] {
+        {
+            let mut f = File::create(testdir.join("f1"))?;
+            writeln!(&mut f, "before")?;
+        }
+        let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+        let mut acc = Vec::with_capacity(4096);
+        for step in 0..2 {
+            if step == 1 {
+                // same size and mtime, like `rsync -t` or `touch -r` would do
+                let f1 = testdir.join("f1");
+                let mtime = f1.metadata()?.modified()?;
+                let mut f = File::create(&f1)?;
+                writeln!(&mut f, "after!")?;
+                f.set_modified(mtime)?;
+                acc.clear();
+            }
+            let proc = TestProcessor::new(&mut acc)?;
+            let tx = db.transaction()?;
+            let walker = MemoizedFsWalker::new(&*tx).change_detector(detector);
+            let mut adder = walker.start_processing(proc)?;
+            let _ = adder.add_path(&testdir, testdir.file_name().unwrap())?;
+            let _ = adder.finish_processing()?;
+            tx.commit()?;
+        }
+        results.push(String::from_utf8(acc)?);
+    }
+
+    // CHECK\
+    assert_eq!(
+        results,
+        vec![
+            "\n".to_string(),
+            format!("\nF|{}\n", testdir.join("f1").display())
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_racy_window() -> Result<()> {
+    let tmpdir = new_tmpdir("test_racy_window")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+
+    let mut results = Vec::new();
+    for step in 0..3 {
+        if step == 1 {
+            // modified during the scan second, the mtime does not move
+            let f1 = testdir.join("f1");
+            let mtime = f1.metadata()?.modified()?;
+            File::create(&f1)?.set_modified(mtime)?;
+            sleep(Duration::from_secs(1));
+        }
+        let mut acc = Vec::with_capacity(4096);
+        let proc = TestProcessor::new(&mut acc)?;
+        let tx = db.transaction()?;
+        let walker = MemoizedFsWalker::new(&*tx).racy_window(Some(Duration::from_millis(500)));
+        let mut adder = walker.start_processing(proc)?;
+        let _ = adder.add_path(&testdir, testdir.file_name().unwrap())?;
+        let _ = adder.finish_processing()?;
+        tx.commit()?;
+        results.push(String::from_utf8(acc)?);
+    }
+
+    // CHECK\
+    // everything was racy after the first run, so everything is verified again
+    assert_eq!(results[0], results[1]);
+    assert!(results[1].contains(&format!("F|{}", testdir.join("f1").display())));
+    assert_eq!(results[2], "\n");
+    Ok(())
+}
+
+#[test]
+fn test_tolerate_errors() -> Result<()> {
+    let tmpdir = new_tmpdir("test_tolerate_errors")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+
+    for parallel in [false, true] {
+        let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+        let mut results = Vec::new();
+        for step in 0..2 {
+            let mut proc = SizeProcessor::default();
+            if step == 1 {
+                let mut f = File::create(testdir.join("f1"))?;
+                writeln!(&mut f, "changed")?;
+                proc.fail_on = Some("f1".into());
+            }
+            let tx = db.transaction()?;
+            let walker = MemoizedFsWalker::new(&*tx)
+                .propagate_changes(true)
+                .change_detector(ChangeDetector::SizeMtime)
+                .tolerate_errors(true);
+            let mut adder = walker.start_processing(proc)?;
+            let entry = if parallel {
+                adder.add_path_parallel(&testdir, testdir.file_name().unwrap())?
+            } else {
+                adder.add_path(&testdir, testdir.file_name().unwrap())?
+            };
+            let (_, report) = adder.finish_processing()?;
+            tx.commit()?;
+            let error_paths: Vec<_> = report.errors.into_iter().map(|e| e.mount_path).collect();
+            results.push((entry.item, error_paths));
+        }
+
+        // CHECK\
+        assert_eq!(
+            results,
+            vec![(0, vec![]), (0, vec![PathBuf::from("asset/f1")])]
+        );
+        // the failing entry is still cached with its previous item
+        let cached: Vec<u8> = db.query_row(
+            "SELECT item FROM fs_walker_cache WHERE path = ?1",
+            [testdir.join("f1").as_os_str().as_bytes()],
+            |row| row.get(0),
+        )?;
+        assert_eq!(sqlite_item_codec(&db)?.decode::<i64>(&cached)?, 0);
+        File::create(testdir.join("f1"))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_deep_tree() -> Result<()> {
+    let tmpdir = new_tmpdir("test_deep_tree")?;
+    let testdir = tmpdir.path().join("asset");
+    let mut deepest = testdir.clone();
+    for _ in 0..1000 {
+        deepest.push("a");
+    }
+    create_dir_all(&deepest)?;
+    File::create(deepest.join("f1"))?;
+
+    let mut sizes = Vec::new();
+    for parallel in [false, true] {
+        let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+        let testdir = testdir.clone();
+        // the walk must not depend on the depth of the tree to fit in a small stack
+        let size = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || -> Result<i64> {
+                let tx = db.transaction()?;
+                let walker = MemoizedFsWalker::new(&*tx).parallelism(2);
+                let mut adder = walker.start_processing(SizeProcessor::default())?;
+                let entry = if parallel {
+                    adder.add_path_parallel(&testdir, "asset")?
+                } else {
+                    adder.add_path(&testdir, "asset")?
+                };
+                let _ = adder.finish_processing()?;
+                tx.commit()?;
+                Ok(entry.item)
+            })?
+            .join()
+            .unwrap()?;
+        sizes.push(size);
+    }
+
+    // CHECK\
+    assert_eq!(sizes, vec![0, 0]);
+    Ok(())
+}
+
+/// item of a processor without any sqlite specific code
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Listing {
+    size: u64,
+    names: Vec<String>,
+}
+
+struct ListingProcessor {
+    processed: usize,
+}
+
+impl FsProcessor for ListingProcessor {
+    type Item = Listing;
+
+    fn process_file(
+        &mut self,
+        path: &Path,
+        _mount_path: &Path,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        self.processed += 1;
+        Ok(Listing {
+            size: path.metadata()?.len(),
+            names: vec![path.file_name().unwrap().to_string_lossy().into_owned()],
+        })
+    }
+
+    fn process_symlink(
+        &mut self,
+        path: &Path,
+        _mount_path: &Path,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        self.processed += 1;
+        Ok(Listing {
+            size: 0,
+            names: vec![path.file_name().unwrap().to_string_lossy().into_owned()],
+        })
+    }
+
+    fn process_special(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        _kind: SpecialKind,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        unreachable!()
+    }
+
+    fn process_folder(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        sub: HashMap<PathBuf, FsEntry<Self::Item>>,
+        _previous: Option<Self::Item>,
+    ) -> Result<Self::Item> {
+        self.processed += 1;
+        let mut names: Vec<_> = sub
+            .into_values()
+            .flat_map(|entry| entry.item.names)
+            .collect();
+        names.sort();
+        Ok(Listing { size: 0, names })
+    }
+}
+
+#[test]
+fn test_item_codecs() -> Result<()> {
+    let tmpdir = new_tmpdir("test_item_codecs")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+
+    let codecs = [
+        ItemCodec::Bincode,
+        #[cfg(feature = "json")]
+        ItemCodec::Json,
+        #[cfg(feature = "cbor")]
+        ItemCodec::Cbor,
+    ];
+    for codec in codecs {
+        let mut db = new_sqlite_cache(&tmpdir, &format!("{}.db", codec.name()))?;
+        set_sqlite_item_codec(&db, codec)?;
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let tx = db.transaction()?;
+            let walker = MemoizedFsWalker::new(&*tx);
+            let mut adder = walker.start_processing(ListingProcessor { processed: 0 })?;
+            let entry = adder.add_path(&testdir, testdir.file_name().unwrap())?;
+            let _ = adder.finish_processing()?;
+            tx.commit()?;
+            results.push(entry.item);
+        }
+
+        // CHECK\
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0].names, vec!["f1", "f2", "f3", "s1"]);
+        assert_eq!(sqlite_item_codec(&db)?, codec);
+        // the items already saved could not be read anymore
+        if codec != ItemCodec::Bincode {
+            assert!(set_sqlite_item_codec(&db, ItemCodec::Bincode).is_err());
+        }
+        set_sqlite_item_codec(&db, codec)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_detect_moves() -> Result<()> {
+    let tmpdir = new_tmpdir("test_detect_moves")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let mut f = File::create(testdir.join("d2/f3"))?;
+    writeln!(&mut f, "some content")?;
+
+    let mut results = Vec::new();
+    for (step, &parallel) in [false, false, true].iter().enumerate() {
+        match step {
+            1 => std::fs::rename(testdir.join("d2"), testdir.join("d1/d4"))?,
+            2 => std::fs::rename(testdir.join("d1/d4"), testdir.join("d4"))?,
+            _ => {}
+        }
+        let proc = SizeProcessor::default();
+        let processed_files = proc.processed_files.clone();
+        let tx = db.transaction()?;
+        let walker = MemoizedFsWalker::new(&*tx).detect_moves(true);
+        let mut adder = walker.start_processing(proc)?;
+        let entry = if parallel {
+            adder.add_path_parallel(&testdir, testdir.file_name().unwrap())?
+        } else {
+            adder.add_path(&testdir, testdir.file_name().unwrap())?
+        };
+        let _ = adder.finish_processing()?;
+        tx.commit()?;
+        results.push((entry.item, processed_files.load(Ordering::SeqCst)));
+    }
+
+    // CHECK\
+    // the moved files are found in the cache instead of being processed again
+    assert_eq!(results, vec![(13, 3), (13, 0), (13, 0)]);
+    let cached = |path: &Path| -> Result<bool> {
+        Ok(db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM fs_walker_cache WHERE path = ?1)",
+            [path.as_os_str().as_bytes()],
+            |row| row.get(0),
+        )?)
+    };
+    assert!(cached(&testdir.join("d4/f3"))?);
+    assert!(!cached(&testdir.join("d2/f3"))?);
+    assert!(!cached(&testdir.join("d1/d4/f3"))?);
+    Ok(())
+}
+
+#[test]
+fn test_large_inodes() -> Result<()> {
+    let tmpdir = new_tmpdir("test_large_inodes")?;
+    let db = new_sqlite_cache(&tmpdir, "cache.db")?;
+    let path = Path::new("/asset/f1");
+    // overlayfs with xino sets the high bits of the inodes
+    let stat = FsStat {
+        mtime: UNIX_EPOCH + Duration::from_secs(1),
+        ctime: UNIX_EPOCH + Duration::from_secs(1),
+        size: u64::MAX,
+        inode: 1 << 63 | 42,
+        device: u64::MAX,
+        content_hash: None,
+        racy: false,
+    };
+
+    // CHECK\
+    let mut session = MemoizedFsCache::<i64>::start_session(&db, "")?;
+    MemoizedFsCacheSession::<i64>::add_root(&mut session, path)?;
+    session.store_entry(path, &stat, 7)?;
+    let lookup = session.lookup_entry(path, &stat, ChangeDetector::InodeCtimeSize, false)?;
+    assert!(matches!(lookup, CacheLookup::Hit(FsEntry { item: 7, .. })));
+    let moved_from = MemoizedFsCacheSession::<i64>::lookup_inode(&mut session, &stat)?;
+    assert_eq!(moved_from, vec![path.to_path_buf()]);
+    MemoizedFsCacheSession::<i64>::end_session(session)?;
+    Ok(())
+}
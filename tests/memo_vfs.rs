@@ -0,0 +1,664 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use sausage::{
+    ChangeDetector, ChangeEvent, ChangeNotifier, ChangeSink, ExcludeRules, FsEntry, FsNode,
+    FsProcessor, MemoizedFsWalker, MemoryCache, MemoryFs, ParallelFsProcessor, SessionReport,
+    SpecialKind, TarProcessor, Vfs, VfsOp,
+};
+use tar_impl::{Archive, EntryType};
+
+mod common;
+use common::*;
+
+use anyhow::{bail, Result};
+
+type Walker<'c> = MemoizedFsWalker<FsNode, &'c mut MemoryCache<FsNode>>;
+
+fn walker<'c>(fs: &Arc<MemoryFs>, cache: &'c mut MemoryCache<FsNode>) -> Walker<'c> {
+    MemoizedFsWalker::new(cache).vfs(fs.clone())
+}
+
+#[test]
+fn test_changes_without_sleep() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(
+        run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?.0,
+        "\n"
+    );
+    update_memory_asset_full_1(&fs, &testdir)?;
+
+    // CHECK\
+    let (changes, _) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(
+        changes,
+        "\nchanged|F|asset/f1\nadded|F|asset/f4\nchanged|D|asset\nremoved|D|asset/d2/d3\n\
+        removed|F|asset/d2/f3\nremoved|S|asset/d2/s1\nremoved|D|asset/d2\n"
+    );
+    assert_eq!(cache.sessions().last().unwrap().started_at, fs.now());
+    assert_eq!(
+        run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?.0,
+        "\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_change_detectors() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(
+        walker(&fs, &mut cache).change_detector(ChangeDetector::ContentHash),
+        fs.clone(),
+        &testdir,
+    )?;
+
+    // CHECK\
+    // rewritten within the same clock tick, only the content tells the file changed
+    fs.write(testdir.join("f2"), "same mtime")?;
+    assert_eq!(
+        run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?.0,
+        "\n"
+    );
+    assert_eq!(
+        run_notifier(
+            walker(&fs, &mut cache).change_detector(ChangeDetector::ContentHash),
+            fs.clone(),
+            &testdir
+        )?
+        .0,
+        "\nchanged|F|asset/f2\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_metadata_changes() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    let detector = ChangeDetector::InodeCtimeSize;
+    run_notifier(
+        walker(&fs, &mut cache).change_detector(detector),
+        fs.clone(),
+        &testdir,
+    )?;
+
+    fs.advance(Duration::from_secs(1));
+    fs.set_mode(testdir.join("f1"), 0o600)?;
+    fs.set_mode(testdir.join("d2"), 0o700)?;
+
+    // CHECK\
+    let (changes, report) = run_notifier(
+        walker(&fs, &mut cache).change_detector(detector),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(changes, "\nmetadata|D|asset/d2\nmetadata|F|asset/f1\n");
+    assert_eq!(report.changes.files.changed, 1);
+    assert_eq!(
+        run_notifier(
+            walker(&fs, &mut cache).change_detector(detector),
+            fs.clone(),
+            &testdir
+        )?
+        .0,
+        "\n"
+    );
+
+    // a rewrite restoring the mtime only changes the ctime, which is not enough for a file
+    let mtime = fs.symlink_metadata(&testdir.join("f2"))?.mtime;
+    fs.advance(Duration::from_secs(1));
+    fs.write(testdir.join("f2"), "\n")?;
+    fs.set_mtime(testdir.join("f2"), mtime)?;
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).change_detector(detector),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(changes, "\nchanged|F|asset/f2\n");
+    Ok(())
+}
+
+#[test]
+fn test_racy_entries() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    let window = Some(Duration::from_secs(1));
+    run_notifier(
+        walker(&fs, &mut cache).racy_window(window),
+        fs.clone(),
+        &testdir,
+    )?;
+
+    // CHECK\
+    // every entry was modified right before the session, nothing is trusted yet
+    let (_, report) = run_notifier(
+        walker(&fs, &mut cache).racy_window(window),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(report.changes.files.unchanged, 0);
+    fs.advance(Duration::from_secs(2));
+    let (_, report) = run_notifier(
+        walker(&fs, &mut cache).racy_window(window),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(report.changes.files.unchanged, 0);
+    let (_, report) = run_notifier(
+        walker(&fs, &mut cache).racy_window(window),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(report.changes.files.unchanged, 3);
+    Ok(())
+}
+
+#[test]
+fn test_fault_injection() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    let cached = cache.len("");
+
+    fs.advance(Duration::from_secs(1));
+    fs.write(testdir.join("d2/f3"), "changed")?;
+    fs.fail(testdir.join("d2"), VfsOp::ReadDir);
+    fs.fail(testdir.join("f1"), VfsOp::Metadata);
+
+    // CHECK\
+    assert!(run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir).is_err());
+    let (changes, report) = run_notifier(
+        walker(&fs, &mut cache).tolerate_errors(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(changes, "\n");
+    let failed: Vec<_> = report.errors.iter().map(|error| &error.path).collect();
+    assert_eq!(failed, vec![&testdir.join("d2"), &testdir.join("f1")]);
+    assert_eq!(cache.len(""), cached);
+
+    fs.clear_faults();
+    let (changes, _) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(changes, "\nchanged|F|asset/d2/f3\n");
+    Ok(())
+}
+
+#[test]
+fn test_failed_session() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    let cached = cache.len("");
+
+    fs.advance(Duration::from_secs(1));
+    fs.write(testdir.join("d2/f3"), "changed")?;
+    fs.write(testdir.join("f1"), "changed")?;
+    // f2 is visited once d2/f3 and f1 are processed
+    fs.fail(testdir.join("f2"), VfsOp::Metadata);
+
+    // CHECK\
+    // like a sqlite transaction that is not committed, a failed session changes nothing
+    assert!(run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir).is_err());
+    assert_eq!(cache.len(""), cached);
+    assert_eq!(cache.sessions().len(), 1);
+
+    fs.clear_faults();
+    let (changes, report) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(changes, "\nchanged|F|asset/d2/f3\nchanged|F|asset/f1\n");
+    assert_eq!(report.session_id, 2);
+    Ok(())
+}
+
+/// sink failing on the changes of the entry at `fail_on`, keeping the other ones
+struct FailingSink<'a> {
+    fail_on: &'a Path,
+    events: &'a mut Vec<ChangeEvent>,
+}
+
+impl ChangeSink for FailingSink<'_> {
+    fn send_change(&mut self, event: ChangeEvent) -> Result<()> {
+        if event.mount_path == self.fail_on {
+            bail!("can not send the change of {}", event.mount_path.display());
+        }
+        self.events.push(event);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_failed_folder_removals() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+
+    fs.advance(Duration::from_secs(1));
+    fs.remove(testdir.join("d2/f3"))?;
+
+    // CHECK\
+    // the removals below a folder are only notified once it is processed
+    let mut events = Vec::new();
+    let sink = FailingSink {
+        fail_on: Path::new("asset/d2"),
+        events: &mut events,
+    };
+    let proc = ChangeNotifier::with_vfs(sink, fs.clone());
+    let mut adder = walker(&fs, &mut cache)
+        .tolerate_errors(true)
+        .start_processing(proc)?;
+    adder.add_path(&testdir, "asset")?;
+    let (_, report) = adder.finish_processing()?;
+    assert_eq!(report.errors.len(), 1);
+    assert!(events.is_empty());
+
+    let (changes, _) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(changes, "\nchanged|D|asset/d2\nremoved|F|asset/d2/f3\n");
+    Ok(())
+}
+
+#[test]
+fn test_exclude_ignore_file() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    fs.write(testdir.join(".gitignore"), "d2\n*.log\n")?;
+    fs.write(testdir.join("f5.log"), "")?;
+
+    // CHECK\
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).exclude(ExcludeRules::gitignore()),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert!(changes.contains("added|F|asset/.gitignore"));
+    assert!(!changes.contains("asset/d2"));
+    assert!(!changes.contains("f5.log"));
+    Ok(())
+}
+
+#[test]
+fn test_exclude_non_utf8_ignore_file() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    fs.write(testdir.join(".gitignore"), b"caf\xe9\nd2\n")?;
+
+    // CHECK\
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).exclude(ExcludeRules::gitignore()),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert!(changes.contains("added|F|asset/.gitignore"));
+    assert!(!changes.contains("asset/d2"));
+    Ok(())
+}
+
+#[test]
+fn test_detect_moves() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(
+        walker(&fs, &mut cache).detect_moves(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    let cached = cache.len("");
+
+    fs.advance(Duration::from_secs(1));
+    fs.rename(testdir.join("d2"), testdir.join("d1/d4"))?;
+    fs.rename(testdir.join("f2"), testdir.join("f5"))?;
+
+    // CHECK\
+    let (changes, report) = run_notifier(
+        walker(&fs, &mut cache).detect_moves(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(
+        changes,
+        "\nmoved|D|asset/d2->asset/d1/d4\nchanged|D|asset/d1\nmoved|F|asset/f2->asset/f5\nchanged|D|asset\n"
+    );
+    assert_eq!(report.changes.folders.moved, 1);
+    assert_eq!(report.changes.files.moved, 1);
+    assert_eq!(report.changes.files.added, 0);
+    assert_eq!(cache.len(""), cached);
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).detect_moves(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(changes, "\n");
+
+    // a moved entry that changed too is processed again at its new path
+    fs.advance(Duration::from_secs(1));
+    fs.rename(testdir.join("f5"), testdir.join("d1/f6"))?;
+    fs.write(testdir.join("d1/f6"), "changed")?;
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).detect_moves(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(
+        changes,
+        "\nmoved|F|asset/f5->asset/d1/f6\nchanged|F|asset/d1/f6\nchanged|D|asset/d1\nchanged|D|asset\n"
+    );
+
+    // the rollback puts the entries back at their old paths
+    cache.rollback_before_session_id("", 2);
+    let (changes, _) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(
+        changes,
+        "\nadded|D|asset/d1/d4/d3\nadded|F|asset/d1/d4/f3\nadded|S|asset/d1/d4/s1\nadded|D|asset/d1/d4\n\
+        added|F|asset/d1/f6\nchanged|D|asset/d1\nchanged|D|asset\nremoved|D|asset/d2/d3\n\
+        removed|F|asset/d2/f3\nremoved|S|asset/d2/s1\nremoved|D|asset/d2\nremoved|F|asset/f2\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_removed_subtree() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+
+    // a folder replaced by a file, its content is removed once the file is saved, descendants first
+    fs.advance(Duration::from_secs(1));
+    fs.remove(testdir.join("d2"))?;
+    fs.write(testdir.join("d2"), "")?;
+
+    // CHECK\
+    let (changes, report) = run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    assert_eq!(
+        changes,
+        "\nremoved|D|asset/d2\nadded|F|asset/d2\nremoved|D|asset/d2/d3\nremoved|F|asset/d2/f3\n\
+        removed|S|asset/d2/s1\nchanged|D|asset\n"
+    );
+    assert_eq!(report.changes.folders.removed, 2);
+    assert_eq!(
+        run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?.0,
+        "\n"
+    );
+
+    // with moves detected, an entry moved out of a removed folder is only reported as moved
+    fs.advance(Duration::from_secs(1));
+    fs.create_dir_all(testdir.join("d1/d5"))?;
+    fs.write(testdir.join("d1/d5/f6"), "")?;
+    run_notifier(walker(&fs, &mut cache), fs.clone(), &testdir)?;
+    fs.advance(Duration::from_secs(1));
+    fs.rename(testdir.join("d1/d5/f6"), testdir.join("f6"))?;
+    fs.remove(testdir.join("d1"))?;
+    let (changes, _) = run_notifier(
+        walker(&fs, &mut cache).detect_moves(true),
+        fs.clone(),
+        &testdir,
+    )?;
+    assert_eq!(
+        changes,
+        "\nmoved|F|asset/d1/d5/f6->asset/f6\nchanged|D|asset\nremoved|D|asset/d1/d5\nremoved|D|asset/d1\n"
+    );
+    Ok(())
+}
+
+/// type, content and header fields of the entries of a tar file
+fn read_tar(data: &[u8]) -> Result<HashMap<String, (EntryType, String, u32, u64)>> {
+    let mut entries = HashMap::new();
+    for entry in Archive::new(data).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let header = entry.header().clone();
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        if let Some(target) = entry.link_name()? {
+            content = target.to_string_lossy().into_owned();
+        }
+        entries.insert(
+            path,
+            (
+                header.entry_type(),
+                content,
+                header.mode()?,
+                header.mtime()?,
+            ),
+        );
+    }
+    Ok(entries)
+}
+
+fn run_tar(walker: Walker, fs: &Arc<MemoryFs>, path: &Path) -> Result<(Vec<u8>, SessionReport)> {
+    let mut data = Vec::new();
+    let proc = TarProcessor::with_vfs(&mut data, fs.clone());
+    let mut adder = walker.start_processing(proc)?;
+    adder.add_path(path, path.file_name().unwrap())?;
+    let (_, report) = adder.finish_processing()?;
+    Ok((data, report))
+}
+
+#[test]
+fn test_tar() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    fs.write(testdir.join("f2"), "content of f2")?;
+    fs.set_mode(testdir.join("f2"), 0o600)?;
+    fs.mknod(testdir.join("fifo"), SpecialKind::Fifo, 0)?;
+    fs.mknod(testdir.join("tty"), SpecialKind::CharDevice, (4 << 8) | 1)?;
+    let (data, report) = run_tar(walker(&fs, &mut cache), &fs, &testdir)?;
+    let full = read_tar(&data)?;
+
+    // CHECK\
+    let mtime = fs.symlink_metadata(&testdir)?.mtime;
+    let mtime = mtime.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    assert_eq!(full.len(), 10);
+    assert_eq!(
+        full["asset/f2"],
+        (EntryType::Regular, "content of f2".into(), 0o600, mtime)
+    );
+    assert_eq!(full["asset/d2"].0, EntryType::Directory);
+    assert_eq!(
+        full["asset/d2/s1"],
+        (EntryType::Symlink, "/asset/d2/f3".into(), 0o777, mtime)
+    );
+    assert_eq!(full["asset/fifo"].0, EntryType::Fifo);
+    assert_eq!(full["asset/tty"].0, EntryType::Char);
+    // only f2 has some content
+    assert_eq!(report.bytes_read, "content of f2".len() as u64);
+
+    update_memory_asset_full_1(&fs, &testdir)?;
+    let diff = read_tar(&run_tar(walker(&fs, &mut cache), &fs, &testdir)?.0)?;
+    let mut paths: Vec<_> = diff.keys().map(String::as_str).collect();
+    paths.sort_unstable();
+    assert_eq!(
+        paths,
+        vec![
+            "asset",
+            "asset/d2.DELETED",
+            "asset/d2/d3.DELETED",
+            "asset/d2/f3.DELETED",
+            "asset/d2/s1.DELETED",
+            "asset/f1",
+            "asset/f4"
+        ]
+    );
+    assert_eq!(diff["asset/f1"].1, "changed\n");
+    assert_eq!(diff["asset/f1"].3, mtime + 1);
+    Ok(())
+}
+
+#[test]
+fn test_tar_moves() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    run_tar(walker(&fs, &mut cache).detect_moves(true), &fs, &testdir)?;
+
+    fs.advance(Duration::from_secs(1));
+    fs.rename(testdir.join("d2"), testdir.join("d1/d4"))?;
+
+    // CHECK\
+    let diff = read_tar(&run_tar(walker(&fs, &mut cache).detect_moves(true), &fs, &testdir)?.0)?;
+    let mut paths: Vec<_> = diff.keys().map(String::as_str).collect();
+    paths.sort_unstable();
+    assert_eq!(paths, vec!["asset", "asset/d1", "asset/d2.MOVED"]);
+    assert_eq!(diff["asset/d2.MOVED"].1, "asset/d1/d4");
+    Ok(())
+}
+
+#[test]
+fn test_tar_metadata() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    fs.write(testdir.join("f2"), "content of f2")?;
+    let detector = ChangeDetector::InodeCtimeSize;
+    run_tar(
+        walker(&fs, &mut cache).change_detector(detector),
+        &fs,
+        &testdir,
+    )?;
+
+    fs.advance(Duration::from_secs(1));
+    fs.set_mode(testdir.join("f2"), 0o600)?;
+
+    // CHECK\
+    let (data, report) = run_tar(
+        walker(&fs, &mut cache).change_detector(detector),
+        &fs,
+        &testdir,
+    )?;
+    let diff = read_tar(&data)?;
+    let mtime = fs.symlink_metadata(&testdir.join("f2"))?.mtime;
+    let mtime = mtime.duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    // only the header of the file is archived again
+    assert_eq!(diff.len(), 1);
+    assert_eq!(report.bytes_read, 0);
+    assert_eq!(
+        diff["asset/f2.METADATA"],
+        (EntryType::Regular, String::new(), 0o600, mtime)
+    );
+
+    // a file whose ctime alone changed, like after an extended attribute change, is archived again,
+    // as a rewrite restoring its mtime looks the same
+    fs.advance(Duration::from_secs(1));
+    fs.set_mode(testdir.join("f2"), 0o600)?;
+    let diff = read_tar(
+        &run_tar(
+            walker(&fs, &mut cache).change_detector(detector),
+            &fs,
+            &testdir,
+        )?
+        .0,
+    )?;
+    assert_eq!(diff.len(), 1);
+    assert_eq!(
+        diff["asset/f2"],
+        (
+            EntryType::Regular,
+            "content of f2".to_string(),
+            0o600,
+            mtime
+        )
+    );
+    Ok(())
+}
+
+/// counts the files under each folder, processes the files from any thread
+struct CountProcessor;
+
+impl FsProcessor for CountProcessor {
+    type Item = u32;
+
+    fn process_file(
+        &mut self,
+        path: &Path,
+        mount_path: &Path,
+        previous: Option<u32>,
+    ) -> Result<u32> {
+        self.process_file_shared(path, mount_path, previous)
+    }
+
+    fn process_symlink(&mut self, _: &Path, _: &Path, _: Option<u32>) -> Result<u32> {
+        Ok(0)
+    }
+
+    fn process_special(
+        &mut self,
+        _: &Path,
+        _: &Path,
+        _: SpecialKind,
+        _: Option<u32>,
+    ) -> Result<u32> {
+        Ok(0)
+    }
+
+    fn process_folder(
+        &mut self,
+        _path: &Path,
+        _mount_path: &Path,
+        sub: HashMap<PathBuf, FsEntry<u32>>,
+        _previous: Option<u32>,
+    ) -> Result<u32> {
+        Ok(sub.values().map(|entry| entry.item).sum())
+    }
+}
+
+impl ParallelFsProcessor for CountProcessor {
+    fn process_file_shared(&self, _: &Path, _: &Path, _: Option<u32>) -> Result<u32> {
+        Ok(1)
+    }
+}
+
+#[test]
+fn test_parallel_fault_injection() -> Result<()> {
+    let fs = Arc::new(MemoryFs::new());
+    let mut cache = MemoryCache::new();
+    let testdir = new_memory_asset_full(&fs, "/asset")?;
+    fs.write(testdir.join("d2/d3/f5"), "")?;
+    let run = |cache: &mut MemoryCache<u32>, tolerate_errors| -> Result<(u32, SessionReport)> {
+        let walker = MemoizedFsWalker::new(cache)
+            .vfs(fs.clone())
+            .parallelism(2)
+            .tolerate_errors(tolerate_errors);
+        let mut adder = walker.start_processing(CountProcessor)?;
+        let entry = adder.add_path_parallel(&testdir, "asset")?;
+        let (_, report) = adder.finish_processing()?;
+        Ok((entry.item, report))
+    };
+    assert_eq!(run(&mut cache, false)?.0, 4);
+
+    // the sub folders are read by the thread pool, their failures are reported like the sequential walk
+    fs.advance(Duration::from_secs(1));
+    fs.write(testdir.join("d2/f3"), "changed")?;
+    fs.fail(testdir.join("d2/d3"), VfsOp::ReadDir);
+    fs.fail(testdir.join("f1"), VfsOp::Metadata);
+
+    // CHECK\
+    assert!(run(&mut cache, false).is_err());
+    let (count, report) = run(&mut cache, true)?;
+    let failed: Vec<_> = report.errors.iter().map(|error| &error.path).collect();
+    assert_eq!(failed, vec![&testdir.join("d2/d3"), &testdir.join("f1")]);
+    // the failed entries keep their previous items
+    assert_eq!(count, 4);
+
+    fs.clear_faults();
+    let (count, report) = run(&mut cache, false)?;
+    assert_eq!(count, 4);
+    assert!(report.errors.is_empty());
+    Ok(())
+}
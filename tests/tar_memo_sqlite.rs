@@ -1,7 +1,11 @@
-use std::{fs::File, path::Path, thread::sleep, time::Duration};
+use std::{
+    collections::HashMap, fs::File, os::unix::net::UnixListener, path::Path, process::Command,
+    thread::sleep, time::Duration,
+};
 
 use rusqlite::Connection;
 use sausage::{rollback_before_session_id, MemoizedFsWalker, TarProcessor};
+use tar_impl::{Archive, EntryType};
 
 mod common;
 use common::*;
@@ -20,7 +24,8 @@ fn run_memoized_walker(
     let walker = MemoizedFsWalker::new(&*tx);
     let mut adder = walker.start_processing(proc)?;
     let _ = adder.add_path(path, path.file_name().unwrap())?;
-    let (_, session_id) = adder.finish_processing()?;
+    let (_, report) = adder.finish_processing()?;
+    let session_id = report.session_id;
     tx.commit()?;
     Ok(session_id)
 }
@@ -42,7 +47,7 @@ fn test_many_run() -> Result<()> {
 
     let id = run_memoized_walker(&mut db, &testdir, tmpdir.path().join("testing-diff.tar"))?;
 
-    rollback_before_session_id(&db, id)?;
+    rollback_before_session_id(&db, "", id)?;
 
     run_memoized_walker(
         &mut db,
@@ -54,3 +59,32 @@ fn test_many_run() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_special_files() -> Result<()> {
+    let tmpdir = new_tmpdir("test_special_files")?;
+    let mut db = new_sqlite_cache(&tmpdir, "cache.db")?;
+
+    let testdir = new_asset_full(&tmpdir, "asset")?;
+    let status = Command::new("mkfifo").arg(testdir.join("fifo")).status()?;
+    assert!(status.success());
+    let _socket = UnixListener::bind(testdir.join("socket"))?;
+
+    let tar_path = tmpdir.path().join("testing-special.tar");
+    run_memoized_walker(&mut db, &testdir, &tar_path)?;
+
+    // CHECK\
+    let mut archive = Archive::new(File::open(&tar_path)?);
+    let mut entries = HashMap::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        entries.insert(entry.path()?.into_owned(), entry.header().entry_type());
+    }
+    assert_eq!(entries.get(Path::new("asset/fifo")), Some(&EntryType::Fifo));
+    assert_eq!(entries.get(Path::new("asset/socket")), None);
+    assert_eq!(
+        entries.get(Path::new("asset/d2/s1")),
+        Some(&EntryType::Symlink)
+    );
+    Ok(())
+}